@@ -17,6 +17,10 @@ local function heartbeat_handler(heartbeat_message, client)
     client:send_binary(response_binary)
 end
 
+local function error_handler(error_message)
+    print("Recieved error from server: " .. tostring(error_message.code) .. " " .. error_message.detail)
+end
+
 local function message_handler(message, client)
     local envelope = pb.decode("bramble.Envelope", message)
 
@@ -24,6 +28,8 @@ local function message_handler(message, client)
         echo_handler(envelope.echo_message)
     elseif envelope.heartbeat_message then
         heartbeat_handler(envelope.heartbeat_message, client)
+    elseif envelope.error_message then
+        error_handler(envelope.error_message)
     end
 end
 
@@ -74,6 +74,81 @@ local function echo_test()
 
 end
 
+local function echo_payload_test()
+    local client = websocket.new(server, port, socket_path)
+    local request_payload = "\0\1\2\255\254binary\0"
+    local response_binary
+
+    local echo_message = {
+        message = "",
+        payload = request_payload,
+    }
+
+    local envelope = {
+        echo_message = echo_message
+    }
+
+    local encoded_msg = pb.encode("bramble.Envelope", envelope)
+
+    function client:onmessage(message)
+        response_binary = message
+        self:close()
+    end
+    function client:onopen()
+        self:send_binary(encoded_msg)
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    local envelope_response = pb.decode("bramble.Envelope", response_binary)
+    local echo_payload = envelope_response.echo_message.payload
+
+    assert(
+        echo_payload == request_payload,
+        string.format("act_payload_len: %s", tostring(echo_payload and #echo_payload))
+    )
+
+end
+
+local function correlation_id_test()
+    local client = websocket.new(server, port, socket_path)
+    local correlation_id = "request-42"
+    local response_binary
+
+    local envelope = {
+        echo_message = { message = "correlated" },
+        correlation_id = correlation_id,
+    }
+
+    local encoded_msg = pb.encode("bramble.Envelope", envelope)
+
+    function client:onmessage(message)
+        response_binary = message
+        self:close()
+    end
+    function client:onopen()
+        self:send_binary(encoded_msg)
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    local envelope_response = pb.decode("bramble.Envelope", response_binary)
+
+    assert(
+        envelope_response.correlation_id == correlation_id,
+        string.format("\nexp_id: %s\nact_id: %s",
+        correlation_id,
+        tostring(envelope_response.correlation_id))
+    )
+
+end
+
 local function heartbeat_test()
     local client = websocket.new(server, port, socket_path)
     local response_binary
@@ -112,5 +187,339 @@ local function heartbeat_test()
 
 end
 
+local function heartbeat_interval_test()
+    local client = websocket.new(server, port, socket_path)
+    local heartbeat_interval_ms = 5000
+    local heartbeat_jitter_ms = 500
+    local response_binary
+
+    local heartbeat_message = {
+        client_id = "test",
+        timestamp = "test",
+    }
+
+    local envelope = {
+        heartbeat_message = heartbeat_message
+    }
+
+    local encoded_msg = pb.encode("bramble.Envelope", envelope)
+
+    function client:onmessage(message)
+        response_binary = message
+        self:close()
+    end
+    function client:onopen()
+        self:send_binary(encoded_msg)
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    local envelope_response = pb.decode("bramble.Envelope", response_binary)
+    local next_interval_ms = envelope_response.heartbeat_message.next_interval_ms
+
+    assert(
+        next_interval_ms >= heartbeat_interval_ms - heartbeat_jitter_ms
+            and next_interval_ms <= heartbeat_interval_ms + heartbeat_jitter_ms,
+        string.format("\nexp_interval: %d +/- %d\nact_interval: %s",
+        heartbeat_interval_ms,
+        heartbeat_jitter_ms,
+        tostring(next_interval_ms))
+    )
+
+end
+
+local function capabilities_test()
+    local client = websocket.new(server, port, socket_path)
+    local response_binary
+
+    local envelope = {
+        capabilities_message = {}
+    }
+
+    local encoded_msg = pb.encode("bramble.Envelope", envelope)
+
+    function client:onmessage(message)
+        response_binary = message
+        self:close()
+    end
+    function client:onopen()
+        self:send_binary(encoded_msg)
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    local envelope_response = pb.decode("bramble.Envelope", response_binary)
+    local capabilities = envelope_response.capabilities_message
+
+    local has_echo_payload = false
+    for _, feature in ipairs(capabilities.features) do
+        if feature == "echo_payload" then
+            has_echo_payload = true
+        end
+    end
+
+    assert(
+        capabilities.server_version ~= "" and has_echo_payload,
+        string.format("act_version: %s", tostring(capabilities.server_version))
+    )
+
+end
+
+local function empty_envelope_test()
+    local client = websocket.new(server, port, socket_path)
+    local response_binary
+
+    local envelope = {}
+
+    local encoded_msg = pb.encode("bramble.Envelope", envelope)
+
+    function client:onmessage(message)
+        response_binary = message
+        self:close()
+    end
+    function client:onopen()
+        self:send_binary(encoded_msg)
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    local envelope_response = pb.decode("bramble.Envelope", response_binary)
+    local error_code = envelope_response.error_message.code
+
+    assert(
+        error_code == "ERROR_CODE_EMPTY_ENVELOPE",
+        string.format("act_code: %s", tostring(error_code))
+    )
+
+end
+
+local function field_too_long_test()
+    local long_string = string.rep("a", 4096)
+    local envelopes = {
+        { echo_message = { message = long_string } },
+        { heartbeat_message = { client_id = long_string, timestamp = "test" } },
+    }
+
+    for _, envelope in ipairs(envelopes) do
+        local client = websocket.new(server, port, socket_path)
+        local response_binary
+
+        local encoded_msg = pb.encode("bramble.Envelope", envelope)
+
+        function client:onmessage(message)
+            response_binary = message
+            self:close()
+        end
+        function client:onopen()
+            self:send_binary(encoded_msg)
+        end
+
+        while client.status ~= websocket.STATUS.CLOSED do
+            client:update()
+            socket.sleep(0.0001)
+        end
+
+        local envelope_response = pb.decode("bramble.Envelope", response_binary)
+        local error_code = envelope_response.error_message and envelope_response.error_message.code
+
+        assert(
+            error_code == "ERROR_CODE_FIELD_TOO_LONG",
+            string.format("act_code: %s", tostring(error_code))
+        )
+    end
+
+end
+
+local function malformed_message_test()
+    local client = websocket.new(server, port, socket_path)
+    local response_binary
+
+    function client:onmessage(message)
+        response_binary = message
+        self:close()
+    end
+    function client:onopen()
+        self:send_binary("\255")
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    local envelope_response = pb.decode("bramble.Envelope", response_binary)
+    local error_message = envelope_response.error_message or {}
+
+    assert(
+        error_message.code == "ERROR_CODE_MALFORMED_MESSAGE",
+        string.format("act_error: %s %s", tostring(error_message.code), tostring(error_message.detail))
+    )
+
+end
+
+local function unsupported_frame_close_test()
+    local client = websocket.new(server, port, socket_path)
+    local close_code, close_reason
+
+    function client:onclose(code, reason)
+        close_code = code
+        close_reason = reason
+    end
+    function client:onopen()
+        self:send("not a binary frame")
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    assert(
+        close_code == 1003 and close_reason == "only binary frames are supported",
+        string.format("act_close: %s %s", tostring(close_code), tostring(close_reason))
+    )
+
+end
+
+local function unsolicited_pong_test()
+    local client = websocket.new(server, port, socket_path)
+    local request_string = "still here"
+    local response_binary, close_code
+
+    local envelope = {
+        echo_message = {
+            message = request_string
+        }
+    }
+
+    local encoded_msg = pb.encode("bramble.Envelope", envelope)
+
+    function client:onmessage(message)
+        response_binary = message
+        self:close()
+    end
+    function client:onclose(code, reason)
+        close_code = code
+    end
+    function client:onopen()
+        self:pong("keepalive")
+        self:send_binary(encoded_msg)
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    assert(response_binary, string.format("act_close: %s", tostring(close_code)))
+
+    local envelope_response = pb.decode("bramble.Envelope", response_binary)
+    local echo_response = envelope_response.echo_message.message
+
+    assert(
+        echo_response == request_string,
+        string.format("\nexp_msg: %s\nact_msg: %s",
+        request_string,
+        echo_response)
+    )
+
+end
+
+local function quarantine_test()
+    local client = websocket.new(server, port, socket_path)
+    local max_invalid_messages = 5
+    local error_count = 0
+    local close_code, close_reason
+
+    local envelope = {
+        echo_message = { message = string.rep("a", 4096) }
+    }
+
+    local encoded_msg = pb.encode("bramble.Envelope", envelope)
+
+    function client:onmessage(message)
+        local envelope_response = pb.decode("bramble.Envelope", message)
+        if envelope_response.error_message then
+            error_count = error_count + 1
+        end
+    end
+    function client:onclose(code, reason)
+        close_code = code
+        close_reason = reason
+    end
+    function client:onopen()
+        for _ = 1, max_invalid_messages do
+            self:send_binary(encoded_msg)
+        end
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    assert(
+        error_count == max_invalid_messages,
+        string.format("\nexp_errors: %d\nact_errors: %d", max_invalid_messages, error_count)
+    )
+    assert(
+        close_code == 1002 and close_reason == "too many invalid messages",
+        string.format("act_close: %s %s", tostring(close_code), tostring(close_reason))
+    )
+
+end
+
+local function unknown_message_test()
+    local client = websocket.new(server, port, socket_path)
+    local response_binary
+
+    -- Field 7 is not an Envelope field this server knows, sent as an empty
+    -- length-delimited value the way a newer client's message variant would be.
+    local encoded_msg = string.char(0x3a, 0x00)
+
+    function client:onmessage(message)
+        response_binary = message
+        self:close()
+    end
+    function client:onopen()
+        self:send_binary(encoded_msg)
+    end
+
+    while client.status ~= websocket.STATUS.CLOSED do
+        client:update()
+        socket.sleep(0.0001)
+    end
+
+    local envelope_response = pb.decode("bramble.Envelope", response_binary)
+    local error_message = envelope_response.error_message or {}
+
+    assert(
+        error_message.code == "ERROR_CODE_UNKNOWN_MESSAGE"
+            and error_message.detail == "unknown message type with tag 7",
+        string.format("act_error: %s %s", tostring(error_message.code), tostring(error_message.detail))
+    )
+
+end
+
 run_test("Echo Test", echo_test)
+run_test("Echo Payload Test", echo_payload_test)
+run_test("Correlation Id Test", correlation_id_test)
 run_test("Heartbeat Test", heartbeat_test)
+run_test("Heartbeat Interval Test", heartbeat_interval_test)
+run_test("Capabilities Test", capabilities_test)
+run_test("Empty Envelope Test", empty_envelope_test)
+run_test("Unknown Message Test", unknown_message_test)
+run_test("Field Too Long Test", field_too_long_test)
+run_test("Malformed Message Test", malformed_message_test)
+run_test("Unsupported Frame Close Test", unsupported_frame_close_test)
+run_test("Unsolicited Pong Test", unsolicited_pong_test)
+run_test("Quarantine Test", quarantine_test)
@@ -1,5 +1,13 @@
+use std::env;
 use std::io::Result;
+use std::path::PathBuf;
 fn main() -> Result<()> {
-    prost_build::compile_protos(&["../protos/auction.proto"], &["../protos/"])?;
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    prost_build::Config::new()
+        .file_descriptor_set_path(out_dir.join("bramble_descriptor.bin"))
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .message_attribute(".", "#[serde(default)]")
+        .enum_attribute(".bramble.Envelope.MessageType", "#[serde(rename_all = \"snake_case\")]")
+        .compile_protos(&["../protos/auction.proto"], &["../protos/"])?;
     Ok(())
 }
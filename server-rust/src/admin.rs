@@ -0,0 +1,68 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use crate::auth::{bearer_token, secrets_match};
+use crate::state::ServerState;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+
+fn authorize(state: &ServerState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(admin_token) = &state.config.admin_token else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    match bearer_token(headers) {
+        Some(token) if secrets_match(token, admin_token) => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+pub async fn dead_letters_handler(State(state): State<Arc<ServerState>>, headers: HeaderMap)
+    -> Result<String, StatusCode>
+{
+    authorize(&state, &headers)?;
+
+    let mut out = String::new();
+    for letter in state.dead_letters.snapshot() {
+        let at = letter.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let client = letter.client.as_deref().unwrap_or("-");
+        writeln!(out, "{} {} {} {}", at, letter.variant, client, letter.error).unwrap();
+    }
+
+    Ok(out)
+}
+
+/// One line per live connection, as space-separated `key=value` pairs.
+pub async fn connections_handler(State(state): State<Arc<ServerState>>, headers: HeaderMap)
+    -> Result<String, StatusCode>
+{
+    authorize(&state, &headers)?;
+
+    let mut connections = state.connections();
+    connections.sort_by_key(|stats| stats.id);
+
+    let mut out = String::new();
+    for stats in connections {
+        let connected_at = stats.connected_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let identity = stats.identity.as_deref().unwrap_or("-");
+        let rtt_ms = stats.last_rtt().map_or("-".to_string(), |rtt| rtt.as_millis().to_string());
+        writeln!(
+            out,
+            "id={} identity={} connected_at={} in_rate={:.3} out_rate={:.3} since_heartbeat={:.3} \
+             bytes_in={} bytes_out={} rtt_ms={} lag_events={}",
+            stats.id,
+            identity,
+            connected_at,
+            stats.inbound_rate(),
+            stats.outbound_rate(),
+            stats.since_last_heartbeat().as_secs_f64(),
+            stats.bytes_in(),
+            stats.bytes_out(),
+            rtt_ms,
+            stats.lag_events(),
+        ).unwrap();
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use crate::config::Config;
+use axum::http::{header, HeaderMap, StatusCode};
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Compares a presented secret with the expected one in time that depends only on
+/// their lengths, so response timing doesn't reveal how much of a guess was right.
+pub fn secrets_match(presented: &str, expected: &str) -> bool {
+    let (presented, expected) = (presented.as_bytes(), expected.as_bytes());
+    if presented.len() != expected.len() {
+        return false;
+    }
+    let difference = presented.iter().zip(expected).fold(0u8, |difference, (a, b)| difference | (a ^ b));
+    std::hint::black_box(difference) == 0
+}
+
+/// Resolves the API key presented on a WebSocket upgrade, either as `?key=` or a bearer
+/// header, to the identity it was issued to. Returns `Ok(None)` when API keys are disabled.
+pub fn authorize_upgrade(config: &Config, params: &HashMap<String, String>, headers: &HeaderMap)
+    -> Result<Option<String>, StatusCode>
+{
+    let Some(api_keys) = &config.api_keys else {
+        return Ok(None);
+    };
+
+    let presented = params.get("key").map(String::as_str).or_else(|| bearer_token(headers));
+
+    match presented.and_then(|key| api_keys.get(key)) {
+        Some(identity) => Ok(Some(identity.clone())),
+        None => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn config_with_keys(keys: &[(&str, &str)]) -> Config {
+        let api_keys = keys.iter().map(|(key, identity)| (key.to_string(), identity.to_string())).collect();
+        Config { api_keys: Some(api_keys), ..Config::default() }
+    }
+
+    fn query(key: &str) -> HashMap<String, String> {
+        HashMap::from([("key".to_string(), key.to_string())])
+    }
+
+    #[test]
+    fn disabled_auth_accepts_anonymous_upgrades() {
+        let result = authorize_upgrade(&Config::default(), &HashMap::new(), &HeaderMap::new());
+        assert_eq!(result, Ok(None));
+    }
+
+    #[test]
+    fn missing_or_unknown_keys_are_rejected() {
+        let config = config_with_keys(&[("k1", "alice")]);
+        assert_eq!(authorize_upgrade(&config, &HashMap::new(), &HeaderMap::new()), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(authorize_upgrade(&config, &query("nope"), &HeaderMap::new()), Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn valid_keys_resolve_to_their_identity() {
+        let config = config_with_keys(&[("k1", "alice"), ("k2", "bob")]);
+        assert_eq!(authorize_upgrade(&config, &query("k1"), &HeaderMap::new()), Ok(Some("alice".to_string())));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer k2"));
+        assert_eq!(authorize_upgrade(&config, &HashMap::new(), &headers), Ok(Some("bob".to_string())));
+    }
+
+    #[test]
+    fn required_auth_without_keys_rejects_everyone() {
+        let config = config_with_keys(&[]);
+        assert_eq!(authorize_upgrade(&config, &query(""), &HeaderMap::new()), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(authorize_upgrade(&config, &HashMap::new(), &HeaderMap::new()), Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn secrets_match_only_exactly() {
+        assert!(secrets_match("s3cret", "s3cret"));
+        assert!(!secrets_match("s3creT", "s3cret"));
+        assert!(!secrets_match("s3cre", "s3cret"));
+        assert!(!secrets_match("s3crets", "s3cret"));
+        assert!(!secrets_match("", "s3cret"));
+        assert!(secrets_match("", ""));
+    }
+}
@@ -0,0 +1,258 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::bramble;
+use axum::http::HeaderValue;
+use prost::Message;
+
+/// Subprotocol clients may request for protobuf-encoded envelopes.
+pub const PROST_PROTOCOL: &str = "bramble.proto";
+
+/// Subprotocol for JSON-encoded envelopes, for reading and writing frames by hand while debugging.
+pub const JSON_PROTOCOL: &str = "bramble.json";
+
+/// Subprotocols offered during upgrade, in order of preference.
+pub const SUPPORTED_PROTOCOLS: [&str; 2] = [PROST_PROTOCOL, JSON_PROTOCOL];
+
+/// Envelope field tags this server understands; a test checks these against auction.proto.
+const KNOWN_ENVELOPE_TAGS: [u32; 5] = [1, 2, 3, 4, 15];
+
+#[derive(Debug)]
+pub struct CodecError(Box<dyn Error + Send + Sync>);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<prost::DecodeError> for CodecError {
+    fn from(err: prost::DecodeError) -> Self {
+        CodecError(Box::new(err))
+    }
+}
+
+impl From<prost::EncodeError> for CodecError {
+    fn from(err: prost::EncodeError) -> Self {
+        CodecError(Box::new(err))
+    }
+}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(err: serde_json::Error) -> Self {
+        CodecError(Box::new(err))
+    }
+}
+
+/// Converts envelopes to and from the bytes carried in binary frames.
+pub trait Codec: Send + Sync {
+    fn decode(&self, bytes: &[u8]) -> Result<bramble::Envelope, CodecError>;
+    fn encode(&self, envelope: &bramble::Envelope) -> Result<Vec<u8>, CodecError>;
+
+    /// For bytes that decoded with no message type, the tag of the unknown variant they carried.
+    fn unknown_message_tag(&self, _bytes: &[u8]) -> Option<u32> {
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ProstCodec;
+
+impl Codec for ProstCodec {
+    fn decode(&self, bytes: &[u8]) -> Result<bramble::Envelope, CodecError> {
+        Ok(bramble::Envelope::decode(bytes)?)
+    }
+
+    fn encode(&self, envelope: &bramble::Envelope) -> Result<Vec<u8>, CodecError> {
+        let mut bin = Vec::with_capacity(envelope.encoded_len());
+        envelope.encode(&mut bin)?;
+        Ok(bin)
+    }
+
+    fn unknown_message_tag(&self, mut bytes: &[u8]) -> Option<u32> {
+        while !bytes.is_empty() {
+            let key = read_varint(&mut bytes)?;
+            let tag = u32::try_from(key >> 3).ok()?;
+            if !KNOWN_ENVELOPE_TAGS.contains(&tag) {
+                return Some(tag);
+            }
+
+            let skip = match key & 0x7 {
+                0 => read_varint(&mut bytes).map(|_| 0)?,
+                1 => 8,
+                2 => usize::try_from(read_varint(&mut bytes)?).ok()?,
+                5 => 4,
+                _ => return None,
+            };
+            bytes = bytes.get(skip..)?;
+        }
+        None
+    }
+}
+
+/// Envelopes as JSON, with fields named as in auction.proto and the oneof under `message_type`.
+/// Missing fields take their protobuf defaults.
+#[derive(Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn decode(&self, bytes: &[u8]) -> Result<bramble::Envelope, CodecError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    fn encode(&self, envelope: &bramble::Envelope) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(envelope)?)
+    }
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Picks the codec for a negotiated subprotocol, falling back to protobuf when none was chosen.
+/// Only `SUPPORTED_PROTOCOLS` are ever negotiated.
+pub fn codec_for_protocol(protocol: Option<&HeaderValue>) -> &'static dyn Codec {
+    match protocol.and_then(|protocol| protocol.to_str().ok()) {
+        Some(JSON_PROTOCOL) => &JsonCodec,
+        _ => &ProstCodec,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bramble::envelope::MessageType;
+    use crate::config::Config;
+    use crate::connection::ConnectionStats;
+    use crate::message_handlers::message_handler;
+    use crate::sink::MockSink;
+    use crate::state::ServerState;
+
+    fn requests() -> Vec<bramble::Envelope> {
+        vec![
+            bramble::Envelope {
+                message_type: Some(MessageType::EchoMessage(bramble::EchoMessage {
+                    message: "hello".to_string(),
+                    payload: Some(vec![0, 1, 255]),
+                })),
+                correlation_id: "request-1".to_string(),
+            },
+            bramble::Envelope {
+                message_type: Some(MessageType::HeartbeatMessage(bramble::HeartbeatMessage {
+                    client_id: "client 7".to_string(),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            },
+            bramble::Envelope {
+                message_type: Some(MessageType::CapabilitiesMessage(bramble::CapabilitiesMessage::default())),
+                ..Default::default()
+            },
+            bramble::Envelope::default(),
+        ]
+    }
+
+    /// Decodes each request with `codec`, handles it, and returns the responses as sent on the wire.
+    async fn exchange(codec: &dyn Codec) -> Vec<bramble::Envelope> {
+        let state = ServerState::new(Config::default());
+        let stats = ConnectionStats::new(1, None, state.config.rate_window_secs);
+        let mut sink = MockSink::default();
+        for request in requests() {
+            let bytes = codec.encode(&request).unwrap();
+            message_handler(&mut sink, &state, &stats, codec.decode(&bytes).unwrap()).await.unwrap();
+        }
+        sink.sent.iter().map(|response| codec.decode(&codec.encode(response).unwrap()).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn codecs_handle_requests_identically() {
+        let prost = exchange(&ProstCodec).await;
+        let json = exchange(&JsonCodec).await;
+
+        assert_eq!(prost.len(), requests().len());
+        assert_eq!(prost, json);
+    }
+
+    #[test]
+    fn json_uses_proto_field_names() {
+        let json = br#"{"message_type": {"echo_message": {"message": "hi"}}, "correlation_id": "c1"}"#;
+
+        let envelope = JsonCodec.decode(json).unwrap();
+
+        assert_eq!(envelope.correlation_id, "c1");
+        let Some(MessageType::EchoMessage(echo)) = envelope.message_type else {
+            panic!("expected an echo, got {:?}", envelope);
+        };
+        assert_eq!(echo.message, "hi");
+        assert_eq!(echo.payload, None);
+    }
+
+    #[test]
+    fn known_tags_match_the_envelope_definition() {
+        let descriptors = include_bytes!(concat!(env!("OUT_DIR"), "/bramble_descriptor.bin"));
+        let descriptors = prost_types::FileDescriptorSet::decode(&descriptors[..]).unwrap();
+        let envelope = descriptors.file.iter()
+            .flat_map(|file| &file.message_type)
+            .find(|message| message.name() == "Envelope")
+            .expect("auction.proto defines Envelope");
+
+        let mut tags: Vec<u32> = envelope.field.iter().map(|field| field.number() as u32).collect();
+        tags.sort_unstable();
+
+        assert_eq!(tags, KNOWN_ENVELOPE_TAGS, "update KNOWN_ENVELOPE_TAGS to match auction.proto");
+    }
+
+    #[test]
+    fn unknown_tags_are_found_past_known_fields() {
+        let mut bytes = bramble::Envelope { correlation_id: "c1".to_string(), ..Default::default() }.encode_to_vec();
+        assert_eq!(ProstCodec.unknown_message_tag(&bytes), None);
+
+        bytes.extend([9 << 3, 1]);
+        assert_eq!(ProstCodec.unknown_message_tag(&bytes), Some(9));
+    }
+
+    #[test]
+    fn unknown_tag_scan_gives_up_on_bad_varints() {
+        // Key varint cut off mid-way.
+        assert_eq!(ProstCodec.unknown_message_tag(&[0x80]), None);
+        // Key varint longer than ten bytes.
+        assert_eq!(ProstCodec.unknown_message_tag(&[0xff; 11]), None);
+        // Known field whose varint value is cut off before the unknown tag.
+        assert_eq!(ProstCodec.unknown_message_tag(&[15 << 3, 0x80]), None);
+        // Tag too large for a u32.
+        assert_eq!(ProstCodec.unknown_message_tag(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x01]), None);
+    }
+
+    #[test]
+    fn unknown_tag_scan_gives_up_on_bad_lengths() {
+        // Correlation id claiming more bytes than follow.
+        assert_eq!(ProstCodec.unknown_message_tag(&[15 << 3 | 2, 5, b'a', 9 << 3, 1]), None);
+        // Length that doesn't fit in usize on any target.
+        let mut bytes = vec![15 << 3 | 2];
+        bytes.extend([0xff; 9]);
+        bytes.push(0x01);
+        assert_eq!(ProstCodec.unknown_message_tag(&bytes), None);
+        // Group wire types, which proto3 doesn't use.
+        assert_eq!(ProstCodec.unknown_message_tag(&[15 << 3 | 3, 9 << 3, 1]), None);
+        assert_eq!(ProstCodec.unknown_message_tag(&[15 << 3 | 4, 9 << 3, 1]), None);
+    }
+
+    #[test]
+    fn negotiated_protocol_picks_the_codec() {
+        let json = codec_for_protocol(Some(&HeaderValue::from_static(JSON_PROTOCOL)));
+        let prost = codec_for_protocol(None);
+        let envelope = bramble::Envelope { correlation_id: "c1".to_string(), ..Default::default() };
+
+        assert_eq!(json.encode(&envelope).unwrap(), br#"{"correlation_id":"c1","message_type":null}"#);
+        assert_eq!(prost.encode(&envelope).unwrap(), envelope.encode_to_vec());
+    }
+}
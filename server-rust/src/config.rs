@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Clone)]
+pub struct Config {
+    pub bind_addr: String,
+    pub log_raw_frames: bool,
+    pub raw_frame_log_limit: usize,
+    pub max_connections: usize,
+    pub connection_retry_after_secs: u64,
+    pub dead_letter_capacity: usize,
+    pub admin_token: Option<String>,
+    /// Maps each accepted API key to the identity it authenticates; `None` disables auth,
+    /// and an empty map refuses every upgrade.
+    pub api_keys: Option<HashMap<String, String>>,
+    pub shutdown_drain_timeout_secs: u64,
+    pub rate_window_secs: u64,
+    pub heartbeat_interval_ms: u32,
+    pub heartbeat_jitter_ms: u32,
+    pub max_string_len: usize,
+    /// Largest frame or reassembled message accepted; bigger ones close the socket with 1009.
+    pub max_message_bytes: usize,
+    /// Consecutive rejected messages after which a connection is quarantined.
+    pub max_invalid_messages: u32,
+    /// How long a quarantined client's address is refused; 0 only closes the connection.
+    pub quarantine_secs: u64,
+    /// How often to send keepalive pings; 0 disables them.
+    pub ping_interval_secs: u64,
+    /// Keepalive round trips slower than this count as lag events.
+    pub lag_threshold_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_addr: "100.76.15.33:3000".to_string(),
+            log_raw_frames: false,
+            raw_frame_log_limit: 256,
+            max_connections: 1024,
+            connection_retry_after_secs: 5,
+            dead_letter_capacity: 100,
+            admin_token: None,
+            api_keys: None,
+            shutdown_drain_timeout_secs: 10,
+            rate_window_secs: 60,
+            heartbeat_interval_ms: 5000,
+            heartbeat_jitter_ms: 500,
+            max_string_len: 1024,
+            max_message_bytes: 64 << 20,
+            max_invalid_messages: 5,
+            quarantine_secs: 0,
+            ping_interval_secs: 15,
+            lag_threshold_ms: 500,
+        }
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("bind_addr", &self.bind_addr)
+            .field("log_raw_frames", &self.log_raw_frames)
+            .field("raw_frame_log_limit", &self.raw_frame_log_limit)
+            .field("max_connections", &self.max_connections)
+            .field("connection_retry_after_secs", &self.connection_retry_after_secs)
+            .field("dead_letter_capacity", &self.dead_letter_capacity)
+            .field("admin_token", &self.admin_token.as_ref().map(|_| REDACTED))
+            .field("api_keys", &ApiKeys(&self.api_keys))
+            .field("shutdown_drain_timeout_secs", &self.shutdown_drain_timeout_secs)
+            .field("rate_window_secs", &self.rate_window_secs)
+            .field("heartbeat_interval_ms", &self.heartbeat_interval_ms)
+            .field("heartbeat_jitter_ms", &self.heartbeat_jitter_ms)
+            .field("max_string_len", &self.max_string_len)
+            .field("max_message_bytes", &self.max_message_bytes)
+            .field("max_invalid_messages", &self.max_invalid_messages)
+            .field("quarantine_secs", &self.quarantine_secs)
+            .field("ping_interval_secs", &self.ping_interval_secs)
+            .field("lag_threshold_ms", &self.lag_threshold_ms)
+            .finish()
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let defaults = Config::default();
+        Config {
+            bind_addr: env::var("BRAMBLE_BIND_ADDR").unwrap_or(defaults.bind_addr),
+            log_raw_frames: env_or("BRAMBLE_LOG_RAW_FRAMES", defaults.log_raw_frames),
+            raw_frame_log_limit: env_or("BRAMBLE_RAW_FRAME_LOG_LIMIT", defaults.raw_frame_log_limit),
+            max_connections: env_or("BRAMBLE_MAX_CONNECTIONS", defaults.max_connections),
+            connection_retry_after_secs: env_or("BRAMBLE_CONNECTION_RETRY_AFTER_SECS", defaults.connection_retry_after_secs),
+            dead_letter_capacity: env_or("BRAMBLE_DEAD_LETTER_CAPACITY", defaults.dead_letter_capacity),
+            admin_token: env::var("BRAMBLE_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            api_keys: resolve_api_keys(
+                env::var("BRAMBLE_API_KEYS").ok(),
+                env::var("BRAMBLE_AUTH_TOKEN").ok().filter(|token| !token.is_empty()),
+            ),
+            shutdown_drain_timeout_secs: env_or("BRAMBLE_SHUTDOWN_DRAIN_TIMEOUT_SECS", defaults.shutdown_drain_timeout_secs),
+            rate_window_secs: env_or("BRAMBLE_RATE_WINDOW_SECS", defaults.rate_window_secs),
+            heartbeat_interval_ms: env_or("BRAMBLE_HEARTBEAT_INTERVAL_MS", defaults.heartbeat_interval_ms),
+            heartbeat_jitter_ms: env_or("BRAMBLE_HEARTBEAT_JITTER_MS", defaults.heartbeat_jitter_ms),
+            max_string_len: env_or("BRAMBLE_MAX_STRING_LEN", defaults.max_string_len),
+            max_message_bytes: env_or("BRAMBLE_MAX_MESSAGE_BYTES", defaults.max_message_bytes),
+            max_invalid_messages: env_or("BRAMBLE_MAX_INVALID_MESSAGES", defaults.max_invalid_messages),
+            quarantine_secs: env_or("BRAMBLE_QUARANTINE_SECS", defaults.quarantine_secs),
+            ping_interval_secs: env_or("BRAMBLE_PING_INTERVAL_SECS", defaults.ping_interval_secs),
+            lag_threshold_ms: env_or("BRAMBLE_LAG_THRESHOLD_MS", defaults.lag_threshold_ms),
+        }
+    }
+}
+
+/// Shows which identities hold keys without showing the keys.
+struct ApiKeys<'a>(&'a Option<HashMap<String, String>>);
+
+impl fmt::Debug for ApiKeys<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(api_keys) = self.0 else {
+            return f.write_str("disabled");
+        };
+
+        let mut identities: Vec<&str> = api_keys.values().map(String::as_str).collect();
+        identities.sort_unstable();
+        write!(f, "{} keys for {:?}", api_keys.len(), identities)
+    }
+}
+
+/// Auth is only disabled when neither `BRAMBLE_API_KEYS` nor the removed `BRAMBLE_AUTH_TOKEN`
+/// is set. Once either is, a value that yields no usable keys refuses every upgrade rather
+/// than letting everyone in.
+fn resolve_api_keys(api_keys: Option<String>, legacy_auth_token: Option<String>) -> Option<HashMap<String, String>> {
+    let legacy = legacy_auth_token.is_some();
+    if legacy {
+        eprintln!("BRAMBLE_AUTH_TOKEN is no longer supported, set BRAMBLE_API_KEYS=key=identity instead");
+    }
+
+    let api_keys = match api_keys {
+        Some(value) => parse_api_keys(&value),
+        None if legacy => HashMap::new(),
+        None => return None,
+    };
+
+    if api_keys.is_empty() {
+        eprintln!("No usable API keys configured, every upgrade will be rejected");
+    }
+    Some(api_keys)
+}
+
+/// Parses `key=identity` pairs separated by commas.
+fn parse_api_keys(value: &str) -> HashMap<String, String> {
+    let mut api_keys = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        match entry.split_once('=') {
+            Some((key, identity)) if !key.is_empty() && !identity.is_empty() => {
+                api_keys.insert(key.to_string(), identity.to_string());
+            }
+            _ => eprintln!("Ignoring malformed API key entry, expected key=identity"),
+        }
+    }
+    api_keys
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    let Ok(value) = env::var(name) else {
+        return default;
+    };
+
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid value {:?} for {}, using default", value, name);
+        default
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unset_api_keys_disable_auth() {
+        assert_eq!(resolve_api_keys(None, None), None);
+    }
+
+    #[test]
+    fn valid_api_keys_are_parsed() {
+        let api_keys = resolve_api_keys(Some("k1=alice, k2=bob".to_string()), None).unwrap();
+        assert_eq!(api_keys.len(), 2);
+        assert_eq!(api_keys["k1"], "alice");
+        assert_eq!(api_keys["k2"], "bob");
+    }
+
+    #[test]
+    fn malformed_api_keys_fail_closed() {
+        for value in ["abc", "abc,=bob,carol=", ""] {
+            assert_eq!(resolve_api_keys(Some(value.to_string()), None), Some(HashMap::new()), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn legacy_auth_token_fails_closed() {
+        assert_eq!(resolve_api_keys(None, Some("secret".to_string())), Some(HashMap::new()));
+
+        let api_keys = resolve_api_keys(Some("k1=alice".to_string()), Some("secret".to_string())).unwrap();
+        assert_eq!(api_keys.len(), 1);
+    }
+
+    #[test]
+    fn debug_output_redacts_secrets() {
+        let config = Config {
+            admin_token: Some("admin-secret".to_string()),
+            api_keys: Some(HashMap::from([
+                ("key-secret-1".to_string(), "alice".to_string()),
+                ("key-secret-2".to_string(), "bob".to_string()),
+            ])),
+            ..Config::default()
+        };
+
+        for output in [format!("{:?}", config), format!("{:#?}", config)] {
+            assert!(!output.contains("admin-secret"), "{}", output);
+            assert!(!output.contains("key-secret"), "{}", output);
+            assert!(output.contains(REDACTED), "{}", output);
+            assert!(output.contains(r#"2 keys for ["alice", "bob"]"#), "{}", output);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::bramble;
+use crate::codec::{codec_for_protocol, Codec};
+use crate::disconnect::DisconnectReason;
+use crate::lock;
+use crate::message_handlers::HandlerError;
+use crate::sink::MessageSink;
+use crate::state::ConnectionSlot;
+use axum::extract::ws::{self, WebSocket};
+use futures::SinkExt;
+
+pub type ConnectionId = u64;
+
+/// Message counts bucketed per second over a sliding window.
+#[derive(Debug)]
+struct RateWindow {
+    window_secs: u64,
+    buckets: VecDeque<(u64, u64)>,
+}
+
+impl RateWindow {
+    fn new(window_secs: u64) -> Self {
+        RateWindow {
+            window_secs: window_secs.max(1),
+            buckets: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, second: u64) {
+        match self.buckets.back_mut() {
+            Some((bucket, count)) if *bucket == second => *count += 1,
+            _ => self.buckets.push_back((second, 1)),
+        }
+        self.evict(second);
+    }
+
+    fn evict(&mut self, second: u64) {
+        while let Some(&(bucket, _)) = self.buckets.front() {
+            if bucket + self.window_secs > second {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    fn rate(&mut self, second: u64, age_secs: f64) -> f64 {
+        self.evict(second);
+        let total: u64 = self.buckets.iter().map(|(_, count)| count).sum();
+        total as f64 / age_secs.min(self.window_secs as f64).max(1.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionStats {
+    pub id: ConnectionId,
+    pub identity: Option<String>,
+    pub connected_at: SystemTime,
+    started: Instant,
+    /// Milliseconds after `started` that the client last showed it was alive.
+    last_heartbeat: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Round trip of the last answered keepalive ping in milliseconds, `u64::MAX` if none yet.
+    last_rtt_ms: AtomicU64,
+    lag_events: AtomicU64,
+    inbound: Mutex<RateWindow>,
+    outbound: Mutex<RateWindow>,
+}
+
+impl ConnectionStats {
+    pub fn new(id: ConnectionId, identity: Option<String>, rate_window_secs: u64) -> Self {
+        ConnectionStats {
+            id,
+            identity,
+            connected_at: SystemTime::now(),
+            started: Instant::now(),
+            last_heartbeat: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            last_rtt_ms: AtomicU64::new(u64::MAX),
+            lag_events: AtomicU64::new(0),
+            inbound: Mutex::new(RateWindow::new(rate_window_secs)),
+            outbound: Mutex::new(RateWindow::new(rate_window_secs)),
+        }
+    }
+
+    pub fn record_inbound(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        let second = self.started.elapsed().as_secs();
+        lock::lock(&self.inbound).record(second);
+    }
+
+    pub fn record_outbound(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        let second = self.started.elapsed().as_secs();
+        lock::lock(&self.outbound).record(second);
+    }
+
+    /// Payload bytes received over the lifetime of the connection, excluding framing.
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes sent over the lifetime of the connection, excluding framing.
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    fn elapsed_millis(&self) -> u64 {
+        u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX)
+    }
+
+    /// Marks the client as alive, e.g. on a heartbeat or an unsolicited pong.
+    pub fn touch_heartbeat(&self) {
+        self.last_heartbeat.store(self.elapsed_millis(), Ordering::Relaxed);
+    }
+
+    /// Records a keepalive round trip, counting it as a lag event if it exceeds `lag_threshold`.
+    pub fn record_rtt(&self, rtt: Duration, lag_threshold: Duration) {
+        let millis = u64::try_from(rtt.as_millis()).unwrap_or(u64::MAX - 1);
+        self.last_rtt_ms.store(millis, Ordering::Relaxed);
+        if rtt > lag_threshold {
+            self.record_lag_event();
+        }
+    }
+
+    pub fn record_lag_event(&self) {
+        self.lag_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Round trip of the last answered keepalive ping.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        match self.last_rtt_ms.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            millis => Some(Duration::from_millis(millis)),
+        }
+    }
+
+    /// Keepalive pings that were answered slowly or not before the next one was due.
+    pub fn lag_events(&self) -> u64 {
+        self.lag_events.load(Ordering::Relaxed)
+    }
+
+    /// Time since the last liveness signal, or since connecting if there was none.
+    pub fn since_last_heartbeat(&self) -> Duration {
+        let last = Duration::from_millis(self.last_heartbeat.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    /// Messages received per second over the sliding window.
+    pub fn inbound_rate(&self) -> f64 {
+        let age = self.started.elapsed();
+        lock::lock(&self.inbound).rate(age.as_secs(), age.as_secs_f64())
+    }
+
+    /// Messages sent per second over the sliding window.
+    pub fn outbound_rate(&self) -> f64 {
+        let age = self.started.elapsed();
+        lock::lock(&self.outbound).rate(age.as_secs(), age.as_secs_f64())
+    }
+}
+
+/// A client's socket together with the slot and stats it was admitted with.
+pub struct Connection {
+    pub socket: WebSocket,
+    slot: ConnectionSlot,
+    codec: &'static dyn Codec,
+    /// Payload and send time of the keepalive ping awaiting a pong.
+    pending_ping: Option<(u64, Instant)>,
+}
+
+impl Connection {
+    pub fn new(socket: WebSocket, slot: ConnectionSlot) -> Self {
+        let codec = codec_for_protocol(socket.protocol());
+        Connection { socket, slot, codec, pending_ping: None }
+    }
+
+    pub fn codec(&self) -> &'static dyn Codec {
+        self.codec
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        self.slot.stats()
+    }
+
+    /// The stats handle, for use while the connection itself is borrowed as a sink.
+    pub fn shared_stats(&self) -> Arc<ConnectionStats> {
+        Arc::clone(self.slot.stats())
+    }
+
+    /// Sends a keepalive ping carrying the connection's age in milliseconds.
+    /// A previous ping still awaiting its pong counts as a lag event and is sent
+    /// again as it was, so a pong slower than the interval still times the whole wait.
+    pub async fn ping(&mut self) -> Result<(), axum::Error> {
+        let sent_at = match self.pending_ping {
+            Some((sent_at, _)) => {
+                self.stats().record_lag_event();
+                sent_at
+            }
+            None => {
+                let sent_at = self.stats().elapsed_millis();
+                self.pending_ping = Some((sent_at, Instant::now()));
+                sent_at
+            }
+        };
+        self.socket.send(ws::Message::Ping(sent_at.to_be_bytes().to_vec().into())).await
+    }
+
+    /// Treats any pong as a liveness signal, and times it if it answers the pending ping.
+    pub fn handle_pong(&mut self, payload: &[u8], lag_threshold: Duration) {
+        self.stats().touch_heartbeat();
+        match self.pending_ping {
+            Some((sent_at, sent)) if payload == sent_at.to_be_bytes() => {
+                self.pending_ping = None;
+                self.stats().record_rtt(sent.elapsed(), lag_threshold);
+            }
+            _ => {}
+        }
+    }
+
+    /// Ends the connection for `reason`, sending the close frame if one is owed.
+    /// Our own sends are flushed as they are made, but the websocket layer's reply
+    /// to a client's close is only queued, so flush that out too. Dropping the
+    /// connection afterwards releases its slot and registry entry.
+    pub async fn disconnect(mut self, reason: DisconnectReason) {
+        if reason.sends_close_frame() {
+            let close = ws::Message::Close(Some(reason.close_frame()));
+            if let Err(err) = self.socket.send(close).await {
+                eprintln!("Failed to send close frame: {}", err);
+            }
+        } else if reason == DisconnectReason::ClientClosed
+            && let Err(err) = SinkExt::flush(&mut self.socket).await
+        {
+            eprintln!("Failed to flush close reply: {}", err);
+        }
+
+        let stats = self.stats();
+        println!(
+            "Client disconnected, cleaning up: connection {} ({}) received {} bytes, sent {} bytes",
+            stats.id,
+            reason.reason(),
+            stats.bytes_in(),
+            stats.bytes_out(),
+        );
+    }
+}
+
+impl MessageSink for Connection {
+    async fn send_envelope(&mut self, envelope: bramble::Envelope) -> Result<(), HandlerError> {
+        let response_bin = self.codec.encode(&envelope).map_err(HandlerError::Encode)?;
+        let bytes = response_bin.len();
+        self.socket.send(ws::Message::binary(response_bin)).await.map_err(HandlerError::Send)?;
+        self.stats().record_outbound(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_counts_messages_per_second_of_age_while_young() {
+        let mut window = RateWindow::new(60);
+        for second in [0, 0, 1, 3] {
+            window.record(second);
+        }
+
+        assert_eq!(window.rate(3, 4.0), 1.0);
+        // Connections younger than a second aren't credited with a burst rate.
+        assert_eq!(RateWindow::new(60).rate(0, 0.25), 0.0);
+        let mut fresh = RateWindow::new(60);
+        fresh.record(0);
+        fresh.record(0);
+        assert_eq!(fresh.rate(0, 0.25), 2.0);
+    }
+
+    #[test]
+    fn rate_only_counts_the_last_window() {
+        let mut window = RateWindow::new(10);
+        for second in 0..5 {
+            window.record(second);
+        }
+        for _ in 0..20 {
+            window.record(100);
+        }
+
+        assert_eq!(window.rate(100, 100.5), 2.0);
+        assert_eq!(window.rate(109, 109.5), 2.0);
+        assert_eq!(window.rate(110, 110.5), 0.0);
+        assert!(window.buckets.is_empty());
+    }
+
+    #[test]
+    fn zero_second_window_is_treated_as_one() {
+        let mut window = RateWindow::new(0);
+        window.record(5);
+        window.record(5);
+
+        assert_eq!(window.rate(5, 5.5), 2.0);
+        assert_eq!(window.rate(6, 6.5), 0.0);
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::lock;
+
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub at: SystemTime,
+    pub variant: &'static str,
+    pub client: Option<String>,
+    pub error: String,
+}
+
+/// Bounded ring buffer of messages whose handlers failed, oldest evicted first.
+#[derive(Debug)]
+pub struct DeadLetterLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<DeadLetter>>,
+}
+
+impl DeadLetterLog {
+    pub fn new(capacity: usize) -> Self {
+        DeadLetterLog {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, letter: DeadLetter) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = lock::lock(&self.entries);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(letter);
+    }
+
+    pub fn snapshot(&self) -> Vec<DeadLetter> {
+        lock::lock(&self.entries).iter().cloned().collect()
+    }
+}
@@ -0,0 +1,49 @@
+use axum::extract::ws::{close_code, CloseCode, CloseFrame};
+
+/// Why a client's connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    ClientClosed,
+    ConnectionLost,
+    Shutdown,
+    UnsupportedFrame,
+    MessageTooBig,
+    Quarantined,
+}
+
+impl DisconnectReason {
+    pub fn close_code(self) -> CloseCode {
+        match self {
+            DisconnectReason::ClientClosed => close_code::NORMAL,
+            DisconnectReason::ConnectionLost => close_code::ABNORMAL,
+            DisconnectReason::Shutdown => close_code::AWAY,
+            DisconnectReason::UnsupportedFrame => close_code::UNSUPPORTED,
+            DisconnectReason::MessageTooBig => close_code::SIZE,
+            DisconnectReason::Quarantined => close_code::PROTOCOL,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            DisconnectReason::ClientClosed => "client closed the connection",
+            DisconnectReason::ConnectionLost => "connection lost",
+            DisconnectReason::Shutdown => "server shutting down",
+            DisconnectReason::UnsupportedFrame => "only binary frames are supported",
+            DisconnectReason::MessageTooBig => "message too big",
+            DisconnectReason::Quarantined => "too many invalid messages",
+        }
+    }
+
+    /// Whether the server still owes the client a close frame; the websocket
+    /// layer answers a client's close itself, and a lost socket can't take one.
+    pub fn sends_close_frame(self) -> bool {
+        !matches!(self, DisconnectReason::ClientClosed | DisconnectReason::ConnectionLost)
+    }
+
+    pub fn close_frame(self) -> CloseFrame {
+        CloseFrame {
+            code: self.close_code(),
+            reason: self.reason().into(),
+        }
+    }
+}
@@ -1,6 +1,18 @@
+pub mod admin;
+pub mod auth;
+pub mod codec;
+pub mod config;
+pub mod connection;
+pub mod dead_letter;
+pub mod disconnect;
+pub mod lock;
 pub mod message_handlers;
+pub mod metrics;
+pub mod server;
+pub mod sink;
+pub mod state;
+pub mod validation;
 
 pub mod bramble {
     include!(concat!(env!("OUT_DIR"), "/bramble.rs"));
 }
-
@@ -0,0 +1,98 @@
+use std::panic::Location;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// The data behind these locks (registries, counters, logs) stays usable if a
+// task panicked mid-update, so recover the guard and clear the poison rather
+// than letting one panic cascade into every later access.
+
+#[track_caller]
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    let caller = Location::caller();
+    mutex.lock().unwrap_or_else(|err| {
+        mutex.clear_poison();
+        recover(err, caller)
+    })
+}
+
+#[track_caller]
+pub fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    let caller = Location::caller();
+    lock.read().unwrap_or_else(|err| {
+        lock.clear_poison();
+        recover(err, caller)
+    })
+}
+
+#[track_caller]
+pub fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    let caller = Location::caller();
+    lock.write().unwrap_or_else(|err| {
+        lock.clear_poison();
+        recover(err, caller)
+    })
+}
+
+fn recover<G>(err: PoisonError<G>, caller: &Location<'_>) -> G {
+    eprintln!("Recovered poisoned lock at {}", caller);
+    err.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn poisoned_mutex_keeps_its_data() {
+        let mutex = Arc::new(Mutex::new(vec![1]));
+        let poisoner = Arc::clone(&mutex);
+        let panicked = thread::spawn(move || {
+            let mut guard = poisoner.lock().unwrap();
+            guard.push(2);
+            panic!("poisoning the mutex");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(mutex.is_poisoned());
+
+        assert_eq!(*lock(&mutex), [1, 2]);
+        assert!(!mutex.is_poisoned());
+    }
+
+    #[test]
+    fn poisoned_rwlock_keeps_its_data() {
+        let rwlock = Arc::new(RwLock::new(vec![1]));
+        let poisoner = Arc::clone(&rwlock);
+        let panicked = thread::spawn(move || {
+            let mut guard = poisoner.write().unwrap();
+            guard.push(2);
+            panic!("poisoning the rwlock");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(rwlock.is_poisoned());
+
+        assert_eq!(*read(&rwlock), [1, 2]);
+        assert!(!rwlock.is_poisoned());
+        write(&rwlock).push(3);
+        assert_eq!(*read(&rwlock), [1, 2, 3]);
+    }
+
+    #[test]
+    fn poisoned_rwlock_can_be_written_first() {
+        let rwlock = Arc::new(RwLock::new(0));
+        let poisoner = Arc::clone(&rwlock);
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.write().unwrap();
+            panic!("poisoning the rwlock");
+        })
+        .join();
+        assert!(rwlock.is_poisoned());
+
+        *write(&rwlock) += 1;
+        assert!(!rwlock.is_poisoned());
+        assert_eq!(*read(&rwlock), 1);
+    }
+}
@@ -1,56 +1,66 @@
-use BrambleWarsServer::bramble::{self, envelope::MessageType};
-use BrambleWarsServer::message_handlers::*;
-use prost::Message;
-use axum::extract::{ws::{self, WebSocket}, WebSocketUpgrade};
-use axum::{response::Response, routing::get, Router};
-
-async fn handler(ws: WebSocketUpgrade) -> Response {
-    ws.on_upgrade(handle_socket)
-}
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-async fn handle_socket(mut socket: WebSocket) {
-    while let Some(message) = socket.recv().await {
-        let Ok(message) = message else {
-            eprintln!("Recieved error message from socket: {:?}", message);
-            return;
-        };
-
-        dbg!(&message);
-
-
-        match message {
-            ws::Message::Binary(ref binary_msg) => {
-                let proto_msg = bramble::Envelope::decode(&binary_msg[..]).expect("Couldn't decode binary message");
-                match proto_msg.message_type {
-                    Some(MessageType::EchoMessage(request)) => {
-                        if echo_handler(&mut socket, request).await.is_err() {
-                            return;
-                        }
-                    }
-                    Some(MessageType::HeartbeatMessage(request)) => {
-                        if heartbeat_handler(&mut socket, request).await.is_err() {
-                            return;
-                        }
-                    }
-                    None => eprintln!("Unhandled message recieved"),
-                };
-            },
-            ws::Message::Close(_) => println!("Socket Closed"),
-            _ => {
-                eprintln!("non binary/close msg recieved, dropping client");
-                return;
-            },
-        };
+use bramble_wars_server::config::Config;
+use bramble_wars_server::server::router;
+use bramble_wars_server::state::ServerState;
+
+async fn shutdown_signal(state: Arc<ServerState>) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Couldn't install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Couldn't install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
+
+    println!("Shutdown requested, draining connections");
+    state.begin_shutdown();
 }
 
+/// Waits for open connections to close, up to the drain timeout. A timeout too
+/// large to represent waits as long as it takes.
+async fn drain_connections(state: &ServerState) {
+    let deadline = Instant::now().checked_add(Duration::from_secs(state.config.shutdown_drain_timeout_secs));
 
+    while state.active_connections() > 0 {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            eprintln!("Shutdown deadline reached with {} connections still open", state.active_connections());
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    println!("All connections drained");
+}
 
 #[tokio::main]
 async fn main() {
-    let app = Router::new()
-        .route("/ws", get(handler));
+    let config = Config::from_env();
+    println!("Effective configuration: {:#?}", config);
+    let bind_addr = config.bind_addr.clone();
+    let state = Arc::new(ServerState::new(config));
+
+    let app = router(Arc::clone(&state));
+
+    let listener = tokio::net::TcpListener::bind(bind_addr).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal(Arc::clone(&state)))
+        .await
+        .unwrap();
 
-    let listener = tokio::net::TcpListener::bind("100.76.15.33:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    drain_connections(&state).await;
 }
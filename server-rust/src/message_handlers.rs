@@ -1,54 +1,295 @@
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::Ordering;
+
 use crate::bramble;
 use crate::bramble::envelope::MessageType;
-use axum::extract::ws::{self, WebSocket};
-use prost::Message;
+use crate::codec::{CodecError, SUPPORTED_PROTOCOLS};
+use crate::config::Config;
+use crate::connection::{ConnectionId, ConnectionStats};
+use crate::sink::MessageSink;
+use crate::state::ServerState;
+
+#[derive(Debug)]
+pub enum HandlerError {
+    /// The socket could not be written to; the client is gone.
+    Send(axum::Error),
+    /// The response envelope could not be encoded.
+    Encode(CodecError),
+    /// The request was understood but could not be processed.
+    BadRequest(String),
+    /// A string field in the request exceeded `max_string_len`.
+    FieldTooLong(String),
+    /// The envelope carried a message tag this server doesn't know.
+    UnknownMessage(u32),
+    /// The frame could not be decoded as an envelope at all.
+    Decode(CodecError),
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::Send(err) => write!(f, "send failed: {}", err),
+            HandlerError::Encode(err) => write!(f, "encode failed: {}", err),
+            HandlerError::BadRequest(detail) => write!(f, "bad request: {}", detail),
+            HandlerError::FieldTooLong(detail) => write!(f, "field too long: {}", detail),
+            HandlerError::UnknownMessage(tag) => write!(f, "unknown message type with tag {}", tag),
+            HandlerError::Decode(err) => write!(f, "could not decode message: {}", err),
+        }
+    }
+}
+
+pub fn message_variant(envelope: &bramble::Envelope) -> &'static str {
+    match envelope.message_type {
+        Some(MessageType::EchoMessage(_)) => "EchoMessage",
+        Some(MessageType::HeartbeatMessage(_)) => "HeartbeatMessage",
+        Some(MessageType::ErrorMessage(_)) => "ErrorMessage",
+        Some(MessageType::CapabilitiesMessage(_)) => "CapabilitiesMessage",
+        None => "None",
+    }
+}
 
-pub async fn echo_handler(socket: &mut WebSocket, request: bramble::EchoMessage)
-    -> Result<(), ()>
+pub fn message_client(envelope: &bramble::Envelope) -> Option<String> {
+    match &envelope.message_type {
+        Some(MessageType::HeartbeatMessage(request)) if !request.client_id.is_empty() => {
+            Some(request.client_id.clone())
+        }
+        _ => None,
+    }
+}
+
+pub async fn message_handler(
+    sink: &mut impl MessageSink,
+    state: &ServerState,
+    stats: &ConnectionStats,
+    envelope: bramble::Envelope,
+) -> Result<(), HandlerError>
+{
+    match envelope.message_type {
+        Some(MessageType::EchoMessage(request)) => echo_handler(sink, request).await,
+        Some(MessageType::HeartbeatMessage(request)) => heartbeat_handler(sink, &state.config, stats, request).await,
+        Some(MessageType::ErrorMessage(request)) => {
+            eprintln!("Received error message from client: {:?}", request);
+            Ok(())
+        }
+        Some(MessageType::CapabilitiesMessage(_)) => capabilities_handler(sink, &state.config).await,
+        None => {
+            eprintln!("Received envelope with no message type set");
+            state.metrics.empty_envelopes.fetch_add(1, Ordering::Relaxed);
+            let code = bramble::ErrorCode::EmptyEnvelope;
+            send_error(sink, code, "envelope has no message type set").await
+        }
+    }
+}
+
+pub async fn echo_handler(sink: &mut impl MessageSink, request: bramble::EchoMessage)
+    -> Result<(), HandlerError>
 {
-    dbg!(&request);
     let response = bramble::EchoMessage {
         message: request.message.to_string(),
+        payload: request.payload,
     };
 
     let response_envelope = bramble::Envelope {
         message_type: Some(MessageType::EchoMessage(response)),
+        ..Default::default()
     };
 
-    let mut response_bin = Vec::new();
-    response_envelope.encode(&mut response_bin).unwrap();
-
-    if socket.send(ws::Message::binary(response_bin)).await.is_err() {
-        return Err(());
-    }
-
-    Ok(())
+    sink.send_envelope(response_envelope).await
 }
 
-pub async fn heartbeat_handler(socket: &mut WebSocket, request: bramble::HeartbeatMessage)
-    -> Result<(), ()>
+pub async fn heartbeat_handler(
+    sink: &mut impl MessageSink,
+    config: &Config,
+    stats: &ConnectionStats,
+    request: bramble::HeartbeatMessage,
+) -> Result<(), HandlerError>
 {
-    dbg!(&request);
-    let client_id = match &request.client_id[..] {
-        "" => "client 0".to_string(),
-        _ => request.client_id.clone(),
+    let client_id = match (stats.identity.as_deref(), &request.client_id[..]) {
+        (Some(identity), _) => identity.to_string(),
+        (None, "") => "client 0".to_string(),
+        (None, _) => request.client_id.clone(),
     };
 
+    let next_interval_ms = next_heartbeat_interval(config, stats.id);
     let response = bramble::HeartbeatMessage {
-        client_id: client_id,
+        client_id,
         timestamp: "test timestamp".to_string(),
+        next_interval_ms,
     };
 
     let response_envelope = bramble::Envelope {
-        message_type: Some(MessageType::HeartbeatMessage(response))
+        message_type: Some(MessageType::HeartbeatMessage(response)),
+        ..Default::default()
     };
 
-    let mut response_bin = Vec::new();
-    response_envelope.encode(&mut response_bin).unwrap();
+    sink.send_envelope(response_envelope).await
+}
 
-    if socket.send(ws::Message::binary(response_bin)).await.is_err() {
-        return Err(());
+pub async fn capabilities_handler(sink: &mut impl MessageSink, config: &Config)
+    -> Result<(), HandlerError>
+{
+    let mut features = vec![
+        "echo_payload".to_string(),
+        "heartbeat_interval".to_string(),
+    ];
+    features.extend(SUPPORTED_PROTOCOLS.iter().map(|protocol| format!("codec:{}", protocol)));
+    if config.api_keys.is_some() {
+        features.push("api_key_auth".to_string());
     }
 
-    Ok(())
+    let response = bramble::CapabilitiesMessage {
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        features,
+        max_string_len: u32::try_from(config.max_string_len).unwrap_or(u32::MAX),
+        max_connections: u32::try_from(config.max_connections).unwrap_or(u32::MAX),
+        heartbeat_interval_ms: config.heartbeat_interval_ms,
+    };
+
+    let response_envelope = bramble::Envelope {
+        message_type: Some(MessageType::CapabilitiesMessage(response)),
+        ..Default::default()
+    };
+
+    sink.send_envelope(response_envelope).await
+}
+
+/// Spreads clients' heartbeats across `heartbeat_interval_ms +/- heartbeat_jitter_ms`,
+/// keeping each connection's offset stable between heartbeats. Seeded from the
+/// connection id, which the client can't choose.
+pub fn next_heartbeat_interval(config: &Config, connection: ConnectionId) -> u32 {
+    let jitter = config.heartbeat_jitter_ms;
+    let mut hasher = DefaultHasher::new();
+    connection.hash(&mut hasher);
+    let offset = (hasher.finish() % (2 * u64::from(jitter) + 1)) as u32;
+
+    config.heartbeat_interval_ms.saturating_sub(jitter).saturating_add(offset)
+}
+
+pub async fn send_error(sink: &mut impl MessageSink, code: bramble::ErrorCode, detail: &str)
+    -> Result<(), HandlerError>
+{
+    let response = bramble::ErrorMessage {
+        code: code.into(),
+        detail: detail.to_string(),
+    };
+
+    let response_envelope = bramble::Envelope {
+        message_type: Some(MessageType::ErrorMessage(response)),
+        ..Default::default()
+    };
+
+    sink.send_envelope(response_envelope).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::sink::MockSink;
+
+    #[tokio::test]
+    async fn empty_envelope_is_answered_and_counted() {
+        let state = ServerState::new(Config::default());
+        let mut sink = MockSink::default();
+        let stats = ConnectionStats::new(1, None, state.config.rate_window_secs);
+
+        message_handler(&mut sink, &state, &stats, bramble::Envelope::default()).await.unwrap();
+        message_handler(&mut sink, &state, &stats, bramble::Envelope::default()).await.unwrap();
+
+        assert_eq!(state.metrics.empty_envelopes.load(Ordering::Relaxed), 2);
+        assert!(state.metrics.render(&[]).contains("empty_envelopes_total 2\n"));
+        for envelope in &sink.sent {
+            let Some(MessageType::ErrorMessage(error)) = &envelope.message_type else {
+                panic!("expected an error message, got {:?}", envelope);
+            };
+            assert_eq!(error.code(), bramble::ErrorCode::EmptyEnvelope);
+        }
+        assert_eq!(sink.sent.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn echo_returns_message_and_payload() {
+        let mut sink = MockSink::default();
+        let request = bramble::EchoMessage {
+            message: "hello".to_string(),
+            payload: Some(vec![0, 1, 2, 255]),
+        };
+
+        echo_handler(&mut sink, request.clone()).await.unwrap();
+
+        assert_eq!(sink.sent, [bramble::Envelope {
+            message_type: Some(MessageType::EchoMessage(request)),
+            ..Default::default()
+        }]);
+    }
+
+    async fn capabilities(config: &Config) -> bramble::CapabilitiesMessage {
+        let mut sink = MockSink::default();
+        capabilities_handler(&mut sink, config).await.unwrap();
+        match sink.sent.pop().and_then(|envelope| envelope.message_type) {
+            Some(MessageType::CapabilitiesMessage(capabilities)) => capabilities,
+            other => panic!("expected capabilities, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn capabilities_reflect_the_config() {
+        let config = Config {
+            api_keys: Some(HashMap::from([("k1".to_string(), "alice".to_string())])),
+            max_string_len: 64,
+            max_connections: 12,
+            heartbeat_interval_ms: 750,
+            ..Config::default()
+        };
+
+        let capabilities = capabilities(&config).await;
+
+        assert_eq!(capabilities.server_version, env!("CARGO_PKG_VERSION"));
+        assert!(capabilities.features.iter().any(|feature| feature == "api_key_auth"), "{:?}", capabilities.features);
+        assert!(capabilities.features.iter().any(|feature| feature == "codec:bramble.json"), "{:?}", capabilities.features);
+        assert_eq!(capabilities.max_string_len, 64);
+        assert_eq!(capabilities.max_connections, 12);
+        assert_eq!(capabilities.heartbeat_interval_ms, 750);
+    }
+
+    #[tokio::test]
+    async fn capabilities_omit_auth_when_disabled() {
+        let capabilities = capabilities(&Config { api_keys: None, ..Config::default() }).await;
+
+        assert!(!capabilities.features.iter().any(|feature| feature == "api_key_auth"), "{:?}", capabilities.features);
+    }
+
+    #[test]
+    fn heartbeat_intervals_stay_within_the_jitter() {
+        let config = Config::default();
+        let (low, high) = (config.heartbeat_interval_ms - config.heartbeat_jitter_ms,
+            config.heartbeat_interval_ms + config.heartbeat_jitter_ms);
+
+        let intervals: Vec<u32> = (1..=1000).map(|id| next_heartbeat_interval(&config, id)).collect();
+
+        assert!(intervals.iter().all(|interval| (low..=high).contains(interval)), "{:?}", intervals);
+        assert!(intervals.iter().any(|&interval| interval != intervals[0]));
+    }
+
+    #[tokio::test]
+    async fn heartbeat_interval_ignores_the_claimed_client_id() {
+        let config = Config::default();
+        let stats = ConnectionStats::new(7, None, config.rate_window_secs);
+        let mut sink = MockSink::default();
+
+        for client_id in ["alice", "bob"] {
+            let request = bramble::HeartbeatMessage { client_id: client_id.to_string(), ..Default::default() };
+            heartbeat_handler(&mut sink, &config, &stats, request).await.unwrap();
+        }
+
+        let intervals: Vec<u32> = sink.sent.iter()
+            .map(|envelope| match &envelope.message_type {
+                Some(MessageType::HeartbeatMessage(response)) => response.next_interval_ms,
+                other => panic!("expected a heartbeat, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(intervals, [next_heartbeat_interval(&config, 7); 2]);
+    }
 }
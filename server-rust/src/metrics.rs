@@ -0,0 +1,65 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::connection::ConnectionStats;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub empty_envelopes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn render(&self, connections: &[Arc<ConnectionStats>]) -> String {
+        let mut out = String::new();
+        writeln!(out, "empty_envelopes_total {}", self.empty_envelopes.load(Ordering::Relaxed)).unwrap();
+        writeln!(out, "connections_active {}", connections.len()).unwrap();
+
+        let mut inbound: Vec<f64> = connections.iter().map(|stats| stats.inbound_rate()).collect();
+        let mut outbound: Vec<f64> = connections.iter().map(|stats| stats.outbound_rate()).collect();
+        write_quantiles(&mut out, "connection_messages_in_per_second", &mut inbound);
+        write_quantiles(&mut out, "connection_messages_out_per_second", &mut outbound);
+        out
+    }
+}
+
+fn write_quantiles(out: &mut String, name: &str, values: &mut [f64]) {
+    values.sort_by(f64::total_cmp);
+    for quantile in [0.5, 0.95] {
+        writeln!(out, "{}{{quantile=\"{}\"}} {:.3}", name, quantile, nearest_rank(values, quantile)).unwrap();
+    }
+}
+
+fn nearest_rank(sorted: &[f64], quantile: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_picks_the_ceiling_rank() {
+        let sorted: Vec<f64> = (1..=10).map(f64::from).collect();
+
+        assert_eq!(nearest_rank(&sorted, 0.5), 5.0);
+        assert_eq!(nearest_rank(&sorted, 0.9), 9.0);
+        assert_eq!(nearest_rank(&sorted, 0.91), 10.0);
+        assert_eq!(nearest_rank(&sorted, 0.99), 10.0);
+        assert_eq!(nearest_rank(&sorted, 1.0), 10.0);
+    }
+
+    #[test]
+    fn nearest_rank_handles_small_inputs() {
+        assert_eq!(nearest_rank(&[], 0.5), 0.0);
+        assert_eq!(nearest_rank(&[7.0], 0.99), 7.0);
+        assert_eq!(nearest_rank(&[1.0, 2.0], 0.0), 1.0);
+        assert_eq!(nearest_rank(&[1.0, 2.0], 0.5), 1.0);
+        assert_eq!(nearest_rank(&[1.0, 2.0], 0.51), 2.0);
+    }
+}
@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+use std::error::Error as _;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::admin::{connections_handler, dead_letters_handler};
+use crate::auth::authorize_upgrade;
+use crate::bramble;
+use crate::codec::{Codec, SUPPORTED_PROTOCOLS};
+use crate::config::Config;
+use crate::connection::{Connection, ConnectionStats};
+use crate::dead_letter::DeadLetter;
+use crate::disconnect::DisconnectReason;
+use crate::message_handlers::*;
+use crate::sink::{Correlated, MessageSink};
+use crate::state::{ConnectionSlot, ServerState};
+use crate::validation::validate_field_lengths;
+use axum::extract::ws::{self, rejection::WebSocketUpgradeRejection, WebSocket};
+use axum::extract::{ConnectInfo, Query, State, WebSocketUpgrade};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{routing::get, Router};
+use tokio::time::MissedTickBehavior;
+
+/// Routes for the game socket, metrics and admin endpoints. Serve it with
+/// `into_make_service_with_connect_info::<SocketAddr>()`; upgrades need the peer address.
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/ws", get(handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/admin/dead-letters", get(dead_letters_handler))
+        .route("/admin/connections", get(connections_handler))
+        .with_state(state)
+}
+
+async fn handler(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    State(state): State<Arc<ServerState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    if state.is_shutting_down() {
+        eprintln!("Server shutting down, rejecting upgrade");
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server is shutting down").into_response();
+    }
+
+    if let Some(remaining) = state.quarantine_remaining(addr.ip()) {
+        eprintln!("Rejected upgrade from quarantined address {}", addr.ip());
+        return (
+            StatusCode::FORBIDDEN,
+            [(header::RETRY_AFTER, remaining.as_secs().max(1).to_string())],
+            "Too many invalid messages, try again later",
+        ).into_response();
+    }
+
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(rejection) => {
+            eprintln!("Rejected malformed upgrade request: {}", rejection);
+            return rejection.into_response();
+        }
+    };
+
+    let identity = match authorize_upgrade(&state.config, &params, &headers) {
+        Ok(identity) => identity,
+        Err(status) => {
+            eprintln!("Rejected unauthorized upgrade request");
+            return status.into_response();
+        }
+    };
+
+    let Some(slot) = ServerState::try_acquire_connection(&state, identity) else {
+        eprintln!("Connection limit of {} reached, rejecting upgrade", state.config.max_connections);
+        let retry_after = state.config.connection_retry_after_secs.to_string();
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after)],
+            "Server is full, try again later",
+        ).into_response();
+    };
+
+    let max_message_bytes = state.config.max_message_bytes;
+    ws.protocols(SUPPORTED_PROTOCOLS)
+        .max_message_size(max_message_bytes)
+        .max_frame_size(max_message_bytes)
+        .on_upgrade(move |socket| handle_socket(socket, state, slot, addr))
+}
+
+async fn metrics_handler(State(state): State<Arc<ServerState>>) -> String {
+    state.metrics.render(&state.connections())
+}
+
+/// Whether a receive failed because a frame or message was over `max_message_bytes`.
+fn is_capacity_error(err: &axum::Error) -> bool {
+    err.source()
+        .and_then(|source| source.downcast_ref::<tungstenite::Error>())
+        .is_some_and(|err| matches!(err, tungstenite::Error::Capacity(_)))
+}
+
+fn payload_len(message: &ws::Message) -> usize {
+    match message {
+        ws::Message::Text(text) => text.len(),
+        ws::Message::Binary(bytes) | ws::Message::Ping(bytes) | ws::Message::Pong(bytes) => bytes.len(),
+        ws::Message::Close(frame) => frame.as_ref().map_or(0, |frame| 2 + frame.reason.len()),
+    }
+}
+
+/// The log line for a received frame, or `None` unless `log_raw_frames` is on.
+/// Frames longer than `raw_frame_log_limit` bytes are truncated.
+fn raw_frame_log(config: &Config, message: &ws::Message) -> Option<String> {
+    if !config.log_raw_frames {
+        return None;
+    }
+
+    let limit = config.raw_frame_log_limit;
+    let line = match message {
+        ws::Message::Binary(bytes) if bytes.len() > limit => {
+            format!("Raw frame: Binary({:?} ... {} more bytes)", &bytes[..limit], bytes.len() - limit)
+        }
+        ws::Message::Text(text) if text.len() > limit => {
+            let end = (0..=limit).rev().find(|&i| text.is_char_boundary(i)).unwrap_or(0);
+            format!("Raw frame: Text({:?} ... {} more bytes)", &text[..end], text.len() - end)
+        }
+        _ => format!("Raw frame: {:?}", message),
+    };
+    Some(line)
+}
+
+/// How long a client must stay silent after shutdown begins before its socket is closed.
+const SHUTDOWN_QUIET_PERIOD: Duration = Duration::from_millis(50);
+
+async fn handle_socket(socket: WebSocket, state: Arc<ServerState>, slot: ConnectionSlot, addr: SocketAddr) {
+    let mut connection = Connection::new(socket, slot);
+    let mut shutdown = state.subscribe_shutdown();
+    let mut draining = false;
+    let mut invalid_messages = 0;
+    let lag_threshold = Duration::from_millis(state.config.lag_threshold_ms);
+    // An interval too long to schedule is as good as never pinging.
+    let now = tokio::time::Instant::now();
+    let ping_period = Duration::from_secs(state.config.ping_interval_secs);
+    let ping_start = now.checked_add(ping_period).filter(|_| !ping_period.is_zero());
+    let pings_enabled = ping_start.is_some();
+    let mut ping = tokio::time::interval_at(ping_start.unwrap_or(now), ping_period.max(Duration::from_secs(1)));
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let reason = loop {
+        // Once shutdown begins, keep answering until the client goes quiet, so requests
+        // already in flight get their replies before the close. The drain deadline bounds this.
+        let message = tokio::select! {
+            biased;
+            message = connection.socket.recv() => message,
+            _ = async { shutdown.wait_for(|&shutting_down| shutting_down).await.is_ok() }, if !draining => {
+                draining = true;
+                continue;
+            }
+            _ = tokio::time::sleep(SHUTDOWN_QUIET_PERIOD), if draining => {
+                break DisconnectReason::Shutdown;
+            }
+            _ = ping.tick(), if pings_enabled && !draining => {
+                if let Err(err) = connection.ping().await {
+                    eprintln!("Failed to send ping, dropping client: {}", err);
+                    break DisconnectReason::ConnectionLost;
+                }
+                continue;
+            }
+        };
+
+        let Some(message) = message else {
+            break DisconnectReason::ConnectionLost;
+        };
+
+        let message = match message {
+            Ok(message) => message,
+            Err(err) if is_capacity_error(&err) => {
+                eprintln!("Message over the size limit, dropping client: {}", err);
+                break DisconnectReason::MessageTooBig;
+            }
+            Err(err) => {
+                eprintln!("Recieved error message from socket: {:?}", err);
+                break DisconnectReason::ConnectionLost;
+            }
+        };
+
+        connection.stats().record_inbound(payload_len(&message));
+
+        if let Some(line) = raw_frame_log(&state.config, &message) {
+            eprintln!("{}", line);
+        }
+
+        match message {
+            ws::Message::Binary(ref binary_msg) => {
+                let stats = connection.shared_stats();
+                let codec = connection.codec();
+                let flow =
+                    handle_binary_frame(&mut connection, &state, &stats, codec, addr.ip(), &mut invalid_messages, binary_msg)
+                        .await;
+                if let ControlFlow::Break(reason) = flow {
+                    break reason;
+                }
+            },
+            ws::Message::Pong(ref payload) => {
+                // Some clients also send unsolicited pongs as keepalives.
+                connection.handle_pong(payload, lag_threshold);
+            },
+            ws::Message::Close(_) => {
+                println!("Socket Closed");
+                break DisconnectReason::ClientClosed;
+            },
+            _ => {
+                eprintln!("non binary/close msg recieved, dropping client");
+                break DisconnectReason::UnsupportedFrame;
+            },
+        };
+    };
+
+    connection.disconnect(reason).await;
+}
+
+/// Dead-letter variant for frames that didn't decode to an envelope.
+const UNDECODABLE: &str = "Undecodable";
+
+/// Decodes, validates and dispatches one binary frame, replying on `sink`.
+/// Breaks with the reason the connection should close for, if it should.
+pub async fn handle_binary_frame<S: MessageSink + Send>(
+    sink: &mut S,
+    state: &ServerState,
+    stats: &ConnectionStats,
+    codec: &dyn Codec,
+    peer: IpAddr,
+    invalid_messages: &mut u32,
+    bytes: &[u8],
+) -> ControlFlow<DisconnectReason>
+{
+    let identity = stats.identity.clone();
+    let proto_msg = match codec.decode(bytes) {
+        Ok(proto_msg) => proto_msg,
+        Err(err) => {
+            let result = Err(HandlerError::Decode(err));
+            return settle(sink, state, peer, invalid_messages, UNDECODABLE, identity, result).await;
+        }
+    };
+    let variant = message_variant(&proto_msg);
+
+    // Validate before reading any field, so an oversized string is never
+    // copied into the dead-letter log or echoed back as a correlation id.
+    let max_len = state.config.max_string_len;
+    let validation = validate_field_lengths(&proto_msg, max_len);
+    let valid = validation.is_ok();
+    if valid && matches!(proto_msg.message_type, Some(bramble::envelope::MessageType::HeartbeatMessage(_))) {
+        stats.touch_heartbeat();
+    }
+    let client = if valid { identity.clone().or_else(|| message_client(&proto_msg)) } else { identity.clone() };
+    let correlation_id =
+        if proto_msg.correlation_id.len() <= max_len { proto_msg.correlation_id.clone() } else { String::new() };
+    let mut sink = Correlated::new(sink, correlation_id);
+
+    let result = match validation {
+        Err(detail) => Err(HandlerError::FieldTooLong(detail)),
+        Ok(()) => {
+            if proto_msg.message_type.is_none()
+                && let Some(tag) = codec.unknown_message_tag(bytes)
+            {
+                Err(HandlerError::UnknownMessage(tag))
+            } else {
+                message_handler(&mut sink, state, stats, proto_msg).await
+            }
+        }
+    };
+    settle(&mut sink, state, peer, invalid_messages, variant, client, result).await
+}
+
+/// Dead-letters a failed message and answers a rejected one, quarantining the peer
+/// once `max_invalid_messages` rejections arrive in a row.
+async fn settle<S: MessageSink + Send>(
+    sink: &mut S,
+    state: &ServerState,
+    peer: IpAddr,
+    invalid_messages: &mut u32,
+    variant: &'static str,
+    client: Option<String>,
+    result: Result<(), HandlerError>,
+) -> ControlFlow<DisconnectReason>
+{
+    if let Err(err) = &result {
+        state.dead_letters.record(DeadLetter {
+            at: SystemTime::now(),
+            variant,
+            client,
+            error: err.to_string(),
+        });
+    }
+
+    let rejection = match result {
+        Ok(()) => None,
+        Err(HandlerError::Send(err)) => {
+            eprintln!("Failed to send to client, dropping client: {}", err);
+            return ControlFlow::Break(DisconnectReason::ConnectionLost);
+        }
+        Err(HandlerError::Encode(err)) => {
+            eprintln!("Failed to encode response, dropping it: {}", err);
+            None
+        }
+        Err(HandlerError::BadRequest(detail)) => Some((bramble::ErrorCode::BadRequest, detail)),
+        Err(HandlerError::FieldTooLong(detail)) => Some((bramble::ErrorCode::FieldTooLong, detail)),
+        Err(err @ HandlerError::UnknownMessage(_)) => Some((bramble::ErrorCode::UnknownMessage, err.to_string())),
+        Err(err @ HandlerError::Decode(_)) => Some((bramble::ErrorCode::MalformedMessage, err.to_string())),
+    };
+
+    let Some((code, detail)) = rejection else {
+        *invalid_messages = 0;
+        return ControlFlow::Continue(());
+    };
+
+    eprintln!("Rejected {}: {}", variant, detail);
+    if send_error(sink, code, &detail).await.is_err() {
+        return ControlFlow::Break(DisconnectReason::ConnectionLost);
+    }
+
+    *invalid_messages += 1;
+    if *invalid_messages >= state.config.max_invalid_messages {
+        eprintln!("Quarantining {} after {} invalid messages", peer, invalid_messages);
+        state.quarantine(peer);
+        return ControlFlow::Break(DisconnectReason::Quarantined);
+    }
+    ControlFlow::Continue(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::codec::ProstCodec;
+    use crate::sink::MockSink;
+    use prost::Message as _;
+
+    /// A sink whose socket has gone away.
+    struct ClosedSink;
+
+    impl MessageSink for ClosedSink {
+        async fn send_envelope(&mut self, _envelope: bramble::Envelope) -> Result<(), HandlerError> {
+            Err(HandlerError::Send(axum::Error::new(io::Error::other("connection reset"))))
+        }
+    }
+
+    /// A sink that can't encode anything, recording what it was asked to send.
+    #[derive(Default)]
+    struct EncodeFailingSink {
+        attempted: Vec<bramble::Envelope>,
+    }
+
+    impl MessageSink for EncodeFailingSink {
+        async fn send_envelope(&mut self, envelope: bramble::Envelope) -> Result<(), HandlerError> {
+            self.attempted.push(envelope);
+            let err = serde_json::from_str::<bramble::Envelope>("not json").unwrap_err();
+            Err(HandlerError::Encode(err.into()))
+        }
+    }
+
+    fn echo_frame(message: &str) -> Vec<u8> {
+        bramble::Envelope {
+            message_type: Some(bramble::envelope::MessageType::EchoMessage(bramble::EchoMessage {
+                message: message.to_string(),
+                payload: None,
+            })),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    async fn handle(sink: &mut (impl MessageSink + Send), state: &ServerState, bytes: &[u8]) -> ControlFlow<DisconnectReason> {
+        let stats = ConnectionStats::new(1, None, state.config.rate_window_secs);
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        handle_binary_frame(sink, state, &stats, &ProstCodec, peer, &mut 0, bytes).await
+    }
+
+    #[tokio::test]
+    async fn send_failure_ends_the_connection() {
+        let state = ServerState::new(Config::default());
+
+        let flow = handle(&mut ClosedSink, &state, &echo_frame("hello")).await;
+
+        assert_eq!(flow, ControlFlow::Break(DisconnectReason::ConnectionLost));
+    }
+
+    #[tokio::test]
+    async fn answered_frames_keep_the_connection() {
+        let state = ServerState::new(Config::default());
+        let mut sink = MockSink::default();
+
+        let flow = handle(&mut sink, &state, &echo_frame("hello")).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(sink.sent.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_messages_are_dead_lettered() {
+        let state = ServerState::new(Config { max_string_len: 4, ..Config::default() });
+        let mut sink = MockSink::default();
+
+        let flow = handle(&mut sink, &state, &echo_frame("far too long")).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let letters = state.dead_letters.snapshot();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].variant, "EchoMessage");
+        assert_eq!(letters[0].client, None);
+        assert!(letters[0].error.starts_with("field too long: "), "{}", letters[0].error);
+    }
+
+    #[tokio::test]
+    async fn unknown_messages_count_towards_quarantine() {
+        let state = ServerState::new(Config { max_invalid_messages: 2, ..Config::default() });
+        let stats = ConnectionStats::new(1, None, state.config.rate_window_secs);
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut sink = MockSink::default();
+        let mut invalid_messages = 0;
+        // Field 9, varint 1: a message type this server doesn't know.
+        let unknown = [9 << 3, 1];
+
+        let first = handle_binary_frame(&mut sink, &state, &stats, &ProstCodec, peer, &mut invalid_messages, &unknown).await;
+        let second = handle_binary_frame(&mut sink, &state, &stats, &ProstCodec, peer, &mut invalid_messages, &unknown).await;
+
+        assert_eq!(first, ControlFlow::Continue(()));
+        assert_eq!(second, ControlFlow::Break(DisconnectReason::Quarantined));
+        assert_eq!(state.dead_letters.snapshot().len(), 2);
+        for envelope in &sink.sent {
+            let Some(bramble::envelope::MessageType::ErrorMessage(error)) = &envelope.message_type else {
+                panic!("expected an error message, got {:?}", envelope);
+            };
+            assert_eq!(error.code(), bramble::ErrorCode::UnknownMessage);
+            assert_eq!(error.detail, "unknown message type with tag 9");
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_quarantine_the_peer_at_the_threshold() {
+        let state = ServerState::new(Config { max_invalid_messages: 3, quarantine_secs: 60, ..Config::default() });
+        let stats = ConnectionStats::new(1, None, state.config.rate_window_secs);
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut sink = MockSink::default();
+        let mut invalid_messages = 0;
+        let garbage = [0xff];
+
+        // A good message in between resets the run of failures.
+        for bytes in [&garbage[..], &garbage, &echo_frame("fine"), &garbage, &garbage] {
+            let flow = handle_binary_frame(&mut sink, &state, &stats, &ProstCodec, peer, &mut invalid_messages, bytes).await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+            assert_eq!(state.quarantine_remaining(peer), None);
+        }
+
+        let flow = handle_binary_frame(&mut sink, &state, &stats, &ProstCodec, peer, &mut invalid_messages, &garbage).await;
+
+        assert_eq!(flow, ControlFlow::Break(DisconnectReason::Quarantined));
+        assert!(state.quarantine_remaining(peer).is_some());
+        let errors: Vec<_> = sink.sent.iter()
+            .filter_map(|envelope| match &envelope.message_type {
+                Some(bramble::envelope::MessageType::ErrorMessage(error)) => Some(error.code()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, [bramble::ErrorCode::MalformedMessage; 5]);
+        let letters = state.dead_letters.snapshot();
+        assert_eq!(letters.len(), 5);
+        assert!(letters.iter().all(|letter| letter.variant == UNDECODABLE));
+        assert!(letters[0].error.starts_with("could not decode message: "), "{}", letters[0].error);
+    }
+
+    #[test]
+    fn raw_frames_are_only_logged_when_enabled() {
+        let frames = [
+            ws::Message::binary(echo_frame("hello")),
+            ws::Message::text("hello"),
+            ws::Message::Ping(vec![1, 2].into()),
+        ];
+
+        let quiet = Config { log_raw_frames: false, ..Config::default() };
+        for frame in &frames {
+            assert_eq!(raw_frame_log(&quiet, frame), None);
+        }
+
+        let verbose = Config { log_raw_frames: true, ..Config::default() };
+        for frame in &frames {
+            assert!(raw_frame_log(&verbose, frame).is_some_and(|line| line.starts_with("Raw frame: ")));
+        }
+    }
+
+    #[test]
+    fn long_raw_frames_are_truncated() {
+        let config = Config { log_raw_frames: true, raw_frame_log_limit: 2, ..Config::default() };
+
+        let binary = raw_frame_log(&config, &ws::Message::binary(vec![1, 2, 3, 4, 5]));
+        let text = raw_frame_log(&config, &ws::Message::text("héllo"));
+
+        assert_eq!(binary.as_deref(), Some("Raw frame: Binary([1, 2] ... 3 more bytes)"));
+        assert_eq!(text.as_deref(), Some("Raw frame: Text(\"h\" ... 5 more bytes)"));
+    }
+
+    #[tokio::test]
+    async fn encode_failure_drops_the_reply_only() {
+        let state = ServerState::new(Config::default());
+        let mut sink = EncodeFailingSink::default();
+
+        let flow = handle(&mut sink, &state, &echo_frame("hello")).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let letters = state.dead_letters.snapshot();
+        assert_eq!(letters.len(), 1);
+        assert!(letters[0].error.starts_with("encode failed: "), "{}", letters[0].error);
+        // Only the echo itself was attempted; no error frame follows it.
+        assert_eq!(sink.attempted.len(), 1);
+        assert!(matches!(sink.attempted[0].message_type, Some(bramble::envelope::MessageType::EchoMessage(_))));
+    }
+}
@@ -0,0 +1,44 @@
+use std::future::Future;
+
+use crate::bramble;
+use crate::message_handlers::HandlerError;
+
+/// Somewhere handlers can send response envelopes to.
+pub trait MessageSink {
+    fn send_envelope(&mut self, envelope: bramble::Envelope)
+        -> impl Future<Output = Result<(), HandlerError>> + Send;
+}
+
+/// Stamps a request's correlation id onto every response sent on its behalf.
+pub struct Correlated<'a, S> {
+    inner: &'a mut S,
+    correlation_id: String,
+}
+
+impl<'a, S> Correlated<'a, S> {
+    pub fn new(inner: &'a mut S, correlation_id: String) -> Self {
+        Correlated { inner, correlation_id }
+    }
+}
+
+impl<S: MessageSink + Send> MessageSink for Correlated<'_, S> {
+    async fn send_envelope(&mut self, mut envelope: bramble::Envelope) -> Result<(), HandlerError> {
+        if envelope.correlation_id.is_empty() {
+            envelope.correlation_id.clone_from(&self.correlation_id);
+        }
+        self.inner.send_envelope(envelope).await
+    }
+}
+
+/// Records every envelope it is sent, for driving handlers without a live socket.
+#[derive(Debug, Default)]
+pub struct MockSink {
+    pub sent: Vec<bramble::Envelope>,
+}
+
+impl MessageSink for MockSink {
+    async fn send_envelope(&mut self, envelope: bramble::Envelope) -> Result<(), HandlerError> {
+        self.sent.push(envelope);
+        Ok(())
+    }
+}
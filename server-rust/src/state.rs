@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::connection::{ConnectionId, ConnectionStats};
+use crate::dead_letter::DeadLetterLog;
+use crate::lock;
+use crate::metrics::Metrics;
+
+/// Stand-in deadline for windows too long to represent as an `Instant`.
+const FAR_FUTURE: Duration = Duration::from_secs(30 * 365 * 24 * 60 * 60);
+
+#[derive(Debug)]
+pub struct ServerState {
+    pub config: Config,
+    pub metrics: Metrics,
+    pub dead_letters: DeadLetterLog,
+    active_connections: AtomicUsize,
+    next_connection_id: AtomicU64,
+    connections: RwLock<HashMap<ConnectionId, Arc<ConnectionStats>>>,
+    quarantined: Mutex<HashMap<IpAddr, Instant>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl ServerState {
+    pub fn new(config: Config) -> Self {
+        ServerState {
+            dead_letters: DeadLetterLog::new(config.dead_letter_capacity),
+            config,
+            metrics: Metrics::default(),
+            active_connections: AtomicUsize::new(0),
+            next_connection_id: AtomicU64::new(1),
+            connections: RwLock::new(HashMap::new()),
+            quarantined: Mutex::new(HashMap::new()),
+            shutdown: watch::Sender::new(false),
+        }
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Acquire)
+    }
+
+    pub fn connections(&self) -> Vec<Arc<ConnectionStats>> {
+        lock::read(&self.connections).values().cloned().collect()
+    }
+
+    /// Refuses upgrades from `addr` for the configured quarantine window, if any.
+    pub fn quarantine(&self, addr: IpAddr) {
+        if self.config.quarantine_secs == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let until = now.checked_add(Duration::from_secs(self.config.quarantine_secs)).unwrap_or(now + FAR_FUTURE);
+        lock::lock(&self.quarantined).insert(addr, until);
+    }
+
+    /// Time left on `addr`'s quarantine, or `None` if it may connect.
+    pub fn quarantine_remaining(&self, addr: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut quarantined = lock::lock(&self.quarantined);
+        quarantined.retain(|_, until| *until > now);
+        quarantined.get(&addr).map(|until| *until - now)
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    pub fn subscribe_shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    /// Reserves a connection slot, or returns `None` if the server is at `max_connections`.
+    pub fn try_acquire_connection(state: &Arc<ServerState>, identity: Option<String>) -> Option<ConnectionSlot> {
+        let max = state.config.max_connections;
+        state.active_connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < max).then_some(count + 1))
+            .ok()?;
+
+        let id = state.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(ConnectionStats::new(id, identity, state.config.rate_window_secs));
+        lock::write(&state.connections).insert(id, Arc::clone(&stats));
+
+        Some(ConnectionSlot { state: Arc::clone(state), stats })
+    }
+}
+
+/// Holds one of the server's connection slots, and its registry entry, until dropped.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    state: Arc<ServerState>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl ConnectionSlot {
+    pub fn stats(&self) -> &Arc<ConnectionStats> {
+        &self.stats
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        lock::write(&self.state.connections).remove(&self.stats.id);
+        self.state.active_connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn unrepresentable_quarantine_falls_back_to_far_future() {
+        let state = ServerState::new(Config { quarantine_secs: u64::MAX, ..Config::default() });
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        state.quarantine(addr);
+
+        let remaining = state.quarantine_remaining(addr).expect("address should be quarantined");
+        assert!(remaining > FAR_FUTURE - Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::bramble;
+use crate::bramble::envelope::MessageType;
+
+/// Checks every string field of a decoded envelope against `max_len` bytes.
+pub fn validate_field_lengths(envelope: &bramble::Envelope, max_len: usize) -> Result<(), String> {
+    let mut fields: Vec<(&str, &str)> = vec![("correlation_id", &envelope.correlation_id)];
+    match &envelope.message_type {
+        Some(MessageType::EchoMessage(request)) => {
+            fields.push(("echo_message.message", &request.message));
+        }
+        Some(MessageType::HeartbeatMessage(request)) => {
+            fields.push(("heartbeat_message.client_id", &request.client_id));
+            fields.push(("heartbeat_message.timestamp", &request.timestamp));
+        }
+        Some(MessageType::ErrorMessage(request)) => {
+            fields.push(("error_message.detail", &request.detail));
+        }
+        Some(MessageType::CapabilitiesMessage(request)) => {
+            fields.push(("capabilities_message.server_version", &request.server_version));
+            fields.extend(request.features.iter().map(|feature| ("capabilities_message.features", feature.as_str())));
+        }
+        None => {}
+    }
+
+    for (name, value) in fields {
+        if value.len() > max_len {
+            return Err(format!("{} is {} bytes, limit is {}", name, value.len(), max_len));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_LEN: usize = 8;
+
+    fn long() -> String {
+        "x".repeat(MAX_LEN + 1)
+    }
+
+    fn envelope(message_type: MessageType) -> bramble::Envelope {
+        bramble::Envelope { message_type: Some(message_type), ..Default::default() }
+    }
+
+    /// One envelope per validated field, with only that field over the limit.
+    fn oversized() -> Vec<(&'static str, bramble::Envelope)> {
+        vec![
+            ("correlation_id", bramble::Envelope { correlation_id: long(), ..Default::default() }),
+            ("echo_message.message", envelope(MessageType::EchoMessage(bramble::EchoMessage {
+                message: long(),
+                payload: None,
+            }))),
+            ("heartbeat_message.client_id", envelope(MessageType::HeartbeatMessage(bramble::HeartbeatMessage {
+                client_id: long(),
+                ..Default::default()
+            }))),
+            ("heartbeat_message.timestamp", envelope(MessageType::HeartbeatMessage(bramble::HeartbeatMessage {
+                timestamp: long(),
+                ..Default::default()
+            }))),
+            ("error_message.detail", envelope(MessageType::ErrorMessage(bramble::ErrorMessage {
+                detail: long(),
+                ..Default::default()
+            }))),
+            ("capabilities_message.server_version", envelope(MessageType::CapabilitiesMessage(bramble::CapabilitiesMessage {
+                server_version: long(),
+                ..Default::default()
+            }))),
+            ("capabilities_message.features", envelope(MessageType::CapabilitiesMessage(bramble::CapabilitiesMessage {
+                features: vec!["ok".to_string(), long()],
+                ..Default::default()
+            }))),
+        ]
+    }
+
+    #[test]
+    fn every_string_field_is_limited() {
+        for (field, envelope) in oversized() {
+            let detail = validate_field_lengths(&envelope, MAX_LEN).expect_err(field);
+            assert_eq!(detail, format!("{} is {} bytes, limit is {}", field, MAX_LEN + 1, MAX_LEN));
+        }
+    }
+
+    #[test]
+    fn fields_at_the_limit_pass() {
+        let at_limit = "x".repeat(MAX_LEN);
+        let envelopes = [
+            bramble::Envelope { correlation_id: at_limit.clone(), ..Default::default() },
+            envelope(MessageType::EchoMessage(bramble::EchoMessage { message: at_limit.clone(), payload: None })),
+            envelope(MessageType::HeartbeatMessage(bramble::HeartbeatMessage {
+                client_id: at_limit.clone(),
+                timestamp: at_limit.clone(),
+                ..Default::default()
+            })),
+            envelope(MessageType::ErrorMessage(bramble::ErrorMessage { detail: at_limit.clone(), ..Default::default() })),
+            envelope(MessageType::CapabilitiesMessage(bramble::CapabilitiesMessage {
+                server_version: at_limit.clone(),
+                features: vec![at_limit.clone(); 3],
+                ..Default::default()
+            })),
+        ];
+
+        for envelope in &envelopes {
+            assert_eq!(validate_field_lengths(envelope, MAX_LEN), Ok(()), "{:?}", envelope);
+        }
+    }
+
+    #[test]
+    fn payload_bytes_are_not_a_string_field() {
+        let envelope = envelope(MessageType::EchoMessage(bramble::EchoMessage {
+            message: String::new(),
+            payload: Some(vec![0; MAX_LEN * 4]),
+        }));
+
+        assert_eq!(validate_field_lengths(&envelope, MAX_LEN), Ok(()));
+    }
+}
@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bramble_wars_server::bramble;
+use bramble_wars_server::bramble::envelope::MessageType;
+use bramble_wars_server::config::Config;
+use bramble_wars_server::server::router;
+use bramble_wars_server::state::ServerState;
+use futures::{SinkExt, StreamExt};
+use prost::Message as _;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{self, http, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn start(config: Config) -> (SocketAddr, Arc<ServerState>) {
+    let state = Arc::new(ServerState::new(config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = router(Arc::clone(&state));
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+    (addr, state)
+}
+
+async fn connect(addr: SocketAddr) -> Result<Client, tungstenite::Error> {
+    connect_with(addr, "/ws").await
+}
+
+async fn connect_with(addr: SocketAddr, path: &str) -> Result<Client, tungstenite::Error> {
+    let (client, _) = tokio_tungstenite::connect_async(format!("ws://{}{}", addr, path)).await?;
+    Ok(client)
+}
+
+/// Plain HTTP/1.1 GET, returning the status code and body.
+async fn http_get(addr: SocketAddr, path: &str, bearer: Option<&str>) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let auth = bearer.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n", path, addr, auth);
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+/// The HTTP response an upgrade was refused with.
+fn rejection(result: Result<Client, tungstenite::Error>) -> http::Response<Option<Vec<u8>>> {
+    match result {
+        Err(tungstenite::Error::Http(response)) => response,
+        Err(err) => panic!("upgrade failed without an HTTP response: {}", err),
+        Ok(_) => panic!("upgrade was accepted"),
+    }
+}
+
+fn echo(message: &str) -> Message {
+    let envelope = bramble::Envelope {
+        message_type: Some(MessageType::EchoMessage(bramble::EchoMessage {
+            message: message.to_string(),
+            payload: None,
+        })),
+        ..Default::default()
+    };
+    Message::binary(envelope.encode_to_vec())
+}
+
+/// Next envelope from the server, skipping control frames.
+async fn recv_envelope(client: &mut Client) -> bramble::Envelope {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for a message")
+            .expect("socket closed")
+            .expect("socket error");
+        match message {
+            Message::Binary(bytes) => return bramble::Envelope::decode(&bytes[..]).unwrap(),
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => panic!("expected a binary frame, got {:?}", other),
+        }
+    }
+}
+
+/// Reads until the server closes the socket, returning its close frame.
+async fn recv_close(client: &mut Client) -> Option<CloseFrame> {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for the close frame");
+        match message {
+            Some(Ok(Message::Close(frame))) => return frame,
+            Some(Ok(_)) => continue,
+            Some(Err(err)) => panic!("socket error before the close frame: {}", err),
+            None => return None,
+        }
+    }
+}
+
+async fn assert_echoes(client: &mut Client, message: &str) {
+    client.send(echo(message)).await.unwrap();
+    match recv_envelope(client).await.message_type {
+        Some(MessageType::EchoMessage(response)) => assert_eq!(response.message, message),
+        other => panic!("expected an echo, got {:?}", other),
+    }
+}
+
+async fn wait_for_connections(state: &ServerState, count: usize) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while state.active_connections() != count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("expected {} connections, have {}", count, state.active_connections()));
+}
+
+#[tokio::test]
+async fn connections_past_the_limit_are_refused_until_a_slot_frees() {
+    let (addr, state) = start(Config {
+        max_connections: 2,
+        connection_retry_after_secs: 7,
+        ..Config::default()
+    }).await;
+
+    let mut first = connect(addr).await.unwrap();
+    let mut second = connect(addr).await.unwrap();
+
+    let refused = rejection(connect(addr).await);
+    assert_eq!(refused.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(refused.headers()[http::header::RETRY_AFTER], "7");
+
+    assert_echoes(&mut first, "first").await;
+    assert_echoes(&mut second, "second").await;
+
+    first.close(None).await.unwrap();
+    wait_for_connections(&state, 1).await;
+
+    let mut third = connect(addr).await.unwrap();
+    assert_echoes(&mut third, "third").await;
+    assert_echoes(&mut second, "second again").await;
+}
+
+#[tokio::test]
+async fn upgrades_need_a_valid_api_key() {
+    let api_keys = HashMap::from([("k1".to_string(), "alice".to_string())]);
+    let (addr, _state) = start(Config { api_keys: Some(api_keys), ..Config::default() }).await;
+
+    let missing = rejection(connect(addr).await);
+    assert_eq!(missing.status(), http::StatusCode::UNAUTHORIZED);
+
+    let wrong = rejection(connect_with(addr, "/ws?key=k2").await);
+    assert_eq!(wrong.status(), http::StatusCode::UNAUTHORIZED);
+
+    let mut client = connect_with(addr, "/ws?key=k1").await.unwrap();
+    let heartbeat = bramble::Envelope {
+        message_type: Some(MessageType::HeartbeatMessage(bramble::HeartbeatMessage {
+            client_id: "spoofed".to_string(),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    client.send(Message::binary(heartbeat.encode_to_vec())).await.unwrap();
+    match recv_envelope(&mut client).await.message_type {
+        Some(MessageType::HeartbeatMessage(response)) => assert_eq!(response.client_id, "alice"),
+        other => panic!("expected a heartbeat, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn non_upgrade_requests_are_rejected() {
+    let (addr, state) = start(Config::default()).await;
+
+    let (status, _) = http_get(addr, "/ws", None).await;
+    assert!((400..500).contains(&status), "status {}", status);
+    assert_eq!(state.active_connections(), 0);
+}
+
+#[tokio::test]
+async fn upgrades_after_shutdown_begins_are_refused() {
+    let (addr, state) = start(Config::default()).await;
+    let mut existing = connect(addr).await.unwrap();
+    assert_echoes(&mut existing, "before shutdown").await;
+
+    state.begin_shutdown();
+
+    let refused = rejection(connect(addr).await);
+    assert_eq!(refused.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+
+    let frame = recv_close(&mut existing).await.expect("expected a close frame");
+    assert_eq!(u16::from(frame.code), 1001);
+    assert_eq!(frame.reason, "server shutting down");
+    wait_for_connections(&state, 0).await;
+}
+
+#[tokio::test]
+async fn oversized_fields_are_rejected_before_being_copied() {
+    let (addr, state) = start(Config { max_string_len: 16, ..Config::default() }).await;
+    let mut client = connect(addr).await.unwrap();
+
+    let oversized = "x".repeat(1000);
+    let heartbeat = bramble::Envelope {
+        message_type: Some(MessageType::HeartbeatMessage(bramble::HeartbeatMessage {
+            client_id: oversized.clone(),
+            ..Default::default()
+        })),
+        correlation_id: oversized.clone(),
+    };
+    client.send(Message::binary(heartbeat.encode_to_vec())).await.unwrap();
+
+    let response = recv_envelope(&mut client).await;
+    assert_eq!(response.correlation_id, "");
+    match response.message_type {
+        Some(MessageType::ErrorMessage(error)) => assert_eq!(error.code, bramble::ErrorCode::FieldTooLong as i32),
+        other => panic!("expected an error, got {:?}", other),
+    }
+
+    let letters = state.dead_letters.snapshot();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].variant, "HeartbeatMessage");
+    assert_eq!(letters[0].client, None);
+    assert!(!letters[0].error.contains(&oversized));
+}
+
+#[tokio::test]
+async fn repeated_malformed_frames_quarantine_the_address() {
+    let (addr, _state) = start(Config { max_invalid_messages: 3, quarantine_secs: 60, ..Config::default() }).await;
+    let mut client = connect(addr).await.unwrap();
+
+    for _ in 0..2 {
+        client.send(Message::binary(vec![0xff])).await.unwrap();
+        match recv_envelope(&mut client).await.message_type {
+            Some(MessageType::ErrorMessage(error)) => assert_eq!(error.code(), bramble::ErrorCode::MalformedMessage),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+    let mut neighbour = connect(addr).await.expect("address quarantined before the threshold");
+    assert_echoes(&mut neighbour, "still admitted").await;
+
+    client.send(Message::binary(vec![0xff])).await.unwrap();
+    let frame = recv_close(&mut client).await.expect("expected a close frame");
+    assert_eq!(u16::from(frame.code), 1002);
+    assert_eq!(frame.reason, "too many invalid messages");
+
+    let refused = rejection(connect(addr).await);
+    assert_eq!(refused.status(), http::StatusCode::FORBIDDEN);
+    assert!(refused.headers().contains_key(http::header::RETRY_AFTER));
+}
+
+#[tokio::test]
+async fn json_subprotocol_speaks_json() {
+    use tungstenite::client::IntoClientRequest;
+
+    let (addr, _state) = start(Config::default()).await;
+    let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+    request.headers_mut().insert(http::header::SEC_WEBSOCKET_PROTOCOL, "bramble.json".parse().unwrap());
+    let (mut client, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+    assert_eq!(response.headers()[http::header::SEC_WEBSOCKET_PROTOCOL], "bramble.json");
+
+    let echo = r#"{"message_type": {"echo_message": {"message": "hi"}}, "correlation_id": "c1"}"#;
+    client.send(Message::binary(echo.as_bytes().to_vec())).await.unwrap();
+
+    let reply = tokio::time::timeout(Duration::from_secs(5), client.next()).await.unwrap().unwrap().unwrap();
+    assert_eq!(
+        reply.into_data(),
+        r#"{"correlation_id":"c1","message_type":{"echo_message":{"message":"hi","payload":null}}}"#.as_bytes()
+    );
+}
+
+/// `/admin/connections` as one key/value map per connection.
+async fn admin_connections(addr: SocketAddr, token: &str) -> Vec<HashMap<String, String>> {
+    let (status, body) = http_get(addr, "/admin/connections", Some(token)).await;
+    assert_eq!(status, 200, "{}", body);
+    body.lines()
+        .map(|line| {
+            line.split(' ')
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or_else(|| panic!("unlabelled column in {:?}", line));
+                    (key.to_string(), value.to_string())
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn admin_lists_each_connection_with_labelled_fields() {
+    let api_keys = HashMap::from([
+        ("k1".to_string(), "alice".to_string()),
+        ("k2".to_string(), "bob".to_string()),
+    ]);
+    let (addr, state) = start(Config {
+        admin_token: Some("admin".to_string()),
+        api_keys: Some(api_keys),
+        ..Config::default()
+    }).await;
+
+    let _alice = connect_with(addr, "/ws?key=k1").await.unwrap();
+    let _bob = connect_with(addr, "/ws?key=k2").await.unwrap();
+    wait_for_connections(&state, 2).await;
+
+    let connections = admin_connections(addr, "admin").await;
+    assert_eq!(connections.len(), 2);
+    let identities: Vec<&str> = connections.iter().map(|connection| &connection["identity"][..]).collect();
+    assert_eq!(identities, ["alice", "bob"]);
+    for connection in &connections {
+        for key in ["id", "connected_at", "in_rate", "out_rate", "since_heartbeat", "bytes_in", "bytes_out", "rtt_ms", "lag_events"] {
+            assert!(connection.contains_key(key), "missing {} in {:?}", key, connection);
+        }
+        assert_eq!(connection["rtt_ms"], "-");
+    }
+    assert_ne!(connections[0]["id"], connections[1]["id"]);
+}
+
+#[tokio::test]
+async fn byte_counts_match_the_payloads_exchanged() {
+    let (addr, _state) = start(Config {
+        admin_token: Some("admin".to_string()),
+        ping_interval_secs: 0,
+        ..Config::default()
+    }).await;
+    let mut client = connect(addr).await.unwrap();
+
+    let request = echo("count these bytes");
+    let request_len = request.len();
+    client.send(request).await.unwrap();
+    let response = recv_envelope(&mut client).await;
+    let heartbeat = bramble::Envelope {
+        message_type: Some(MessageType::HeartbeatMessage(bramble::HeartbeatMessage::default())),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    client.send(Message::binary(heartbeat.clone())).await.unwrap();
+    let heartbeat_response = recv_envelope(&mut client).await;
+
+    // The last reply is counted once its send returns, which can be just after it arrives.
+    let expected_in = request_len + heartbeat.len();
+    let expected_out = response.encoded_len() + heartbeat_response.encoded_len();
+    let connection = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let connections = admin_connections(addr, "admin").await;
+            if connections[0]["bytes_out"] == expected_out.to_string() {
+                return connections.into_iter().next().unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("bytes_out never reached the size of the replies");
+    assert_eq!(connection["bytes_in"], expected_in.to_string());
+    assert_eq!(response.encoded_len(), request_len);
+}
+
+#[tokio::test]
+async fn oversized_messages_close_with_1009() {
+    let (addr, state) = start(Config { max_message_bytes: 1024, ..Config::default() }).await;
+    let mut client = connect(addr).await.unwrap();
+    assert_echoes(&mut client, "small enough").await;
+
+    client.send(echo(&"x".repeat(2048))).await.unwrap();
+
+    let frame = recv_close(&mut client).await.expect("expected a close frame");
+    assert_eq!(u16::from(frame.code), 1009);
+    assert_eq!(frame.reason, "message too big");
+    wait_for_connections(&state, 0).await;
+}
+
+#[tokio::test]
+async fn unschedulable_ping_interval_disables_pings() {
+    let (addr, _state) = start(Config { ping_interval_secs: u64::MAX, ..Config::default() }).await;
+    let mut client = connect(addr).await.unwrap();
+
+    assert_echoes(&mut client, "still served").await;
+}
+
+#[tokio::test]
+async fn late_pongs_report_the_full_round_trip() {
+    let (addr, _state) = start(Config {
+        admin_token: Some("admin".to_string()),
+        ping_interval_secs: 1,
+        lag_threshold_ms: 100,
+        ..Config::default()
+    }).await;
+    let mut client = connect(addr).await.unwrap();
+
+    // Leave the first ping unread past the second tick; the pong is only sent once it's read.
+    tokio::time::sleep(Duration::from_millis(2300)).await;
+    let message = tokio::time::timeout(Duration::from_secs(5), client.next()).await.unwrap().unwrap().unwrap();
+    assert!(matches!(message, Message::Ping(_)), "expected a ping, got {:?}", message);
+    client.flush().await.unwrap();
+
+    let connection = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let connections = admin_connections(addr, "admin").await;
+            if connections[0]["rtt_ms"] != "-" {
+                return connections.into_iter().next().unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no round trip was recorded");
+
+    let rtt_ms: u64 = connection["rtt_ms"].parse().unwrap();
+    let lag_events: u64 = connection["lag_events"].parse().unwrap();
+    assert!(rtt_ms >= 1200, "rtt_ms {} should time the first ping", rtt_ms);
+    // One for the tick that found the ping unanswered, one for the slow round trip.
+    assert!(lag_events >= 2, "lag_events {}", lag_events);
+}
+
+#[tokio::test]
+async fn requests_in_flight_at_shutdown_are_answered_before_the_close() {
+    let (addr, state) = start(Config::default()).await;
+    let mut client = connect(addr).await.unwrap();
+
+    let requests = 50;
+    for i in 0..requests {
+        client.feed(echo(&format!("request {}", i))).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    state.begin_shutdown();
+
+    for i in 0..requests {
+        match recv_envelope(&mut client).await.message_type {
+            Some(MessageType::EchoMessage(response)) => assert_eq!(response.message, format!("request {}", i)),
+            other => panic!("expected an echo, got {:?}", other),
+        }
+    }
+    let frame = recv_close(&mut client).await.expect("expected a close frame");
+    assert_eq!(u16::from(frame.code), 1001);
+    wait_for_connections(&state, 0).await;
+}
+
+
+#[tokio::test]
+async fn every_disconnect_cause_closes_and_cleans_up() {
+    let client_close = CloseFrame { code: 1000.into(), reason: "bye".into() };
+    let causes = [
+        ("unsupported frame", Message::text("hello"), 1003, "only binary frames are supported"),
+        ("quarantine", Message::binary(vec![0xff]), 1002, "too many invalid messages"),
+        ("message too big", echo(&"x".repeat(2048)), 1009, "message too big"),
+        ("client close", Message::Close(Some(client_close)), 1000, "bye"),
+    ];
+
+    for (cause, message, code, reason) in causes {
+        let (addr, state) = start(Config {
+            admin_token: Some("admin".to_string()),
+            max_invalid_messages: 1,
+            max_message_bytes: 1024,
+            ..Config::default()
+        }).await;
+        let mut client = connect(addr).await.unwrap();
+        wait_for_connections(&state, 1).await;
+        assert_eq!(admin_connections(addr, "admin").await.len(), 1);
+
+        client.send(message).await.unwrap();
+
+        let frame = recv_close(&mut client).await.unwrap_or_else(|| panic!("{}: expected a close frame", cause));
+        assert_eq!(u16::from(frame.code), code, "{}", cause);
+        assert_eq!(frame.reason, reason, "{}", cause);
+        wait_for_connections(&state, 0).await;
+        assert!(admin_connections(addr, "admin").await.is_empty(), "{}: registry entry left behind", cause);
+    }
+}
+
+#[tokio::test]
+async fn unsolicited_pongs_refresh_liveness() {
+    let (addr, _state) = start(Config {
+        admin_token: Some("admin".to_string()),
+        ping_interval_secs: 0,
+        ..Config::default()
+    }).await;
+    let mut client = connect(addr).await.unwrap();
+    let since_heartbeat = |connections: &[HashMap<String, String>]| -> f64 {
+        connections[0]["since_heartbeat"].parse().unwrap()
+    };
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let idle = since_heartbeat(&admin_connections(addr, "admin").await);
+    assert!(idle >= 0.4, "since_heartbeat {} before the pong", idle);
+
+    client.send(Message::Pong(b"keepalive"[..].into())).await.unwrap();
+    assert_echoes(&mut client, "still connected").await;
+
+    let refreshed = since_heartbeat(&admin_connections(addr, "admin").await);
+    assert!(refreshed < 0.2, "since_heartbeat {} after the pong", refreshed);
+}
+
+#[tokio::test]
+async fn admin_endpoints_need_the_exact_token() {
+    let (addr, _state) = start(Config { admin_token: Some("admin".to_string()), ..Config::default() }).await;
+
+    for path in ["/admin/connections", "/admin/dead-letters"] {
+        assert_eq!(http_get(addr, path, Some("admin")).await.0, 200, "{}", path);
+        for wrong in [None, Some("admin2"), Some("admiN"), Some("")] {
+            assert_eq!(http_get(addr, path, wrong).await.0, 401, "{} with {:?}", path, wrong);
+        }
+    }
+}